@@ -25,6 +25,8 @@ fn main() -> anyhow::Result<()> {
 			continue
 		}
 
+		// glslc_path is only a reference on some platforms.
+		#[allow(clippy::needless_borrow)]
 		compile_glsl(&glslc_path, &shader_path)?;
 	}
 
@@ -59,6 +61,7 @@ fn compile_glsl(compiler_path: &Path, shader_path: &Path) -> anyhow::Result<()>
 
 	println!("cargo:rerun-if-changed={}", target_path.display());
 
+	#[allow(clippy::needless_borrows_for_generic_args)]
 	let exit_status = Command::new(compiler_path)
 		.stdout(Stdio::inherit())
 		.stderr(std::io::stderr())
@@ -1,9 +1,73 @@
 #version 450
+#extension GL_EXT_buffer_reference: require
+#extension GL_EXT_scalar_block_layout: require
+
+// Must match output_encoding in main.rs
+const uint OUTPUT_ENCODING_SRGB = 0;
+const uint OUTPUT_ENCODING_SCRGB = 1;
+const uint OUTPUT_ENCODING_HDR10 = 2;
+const uint OUTPUT_ENCODING_DISPLAY_P3 = 3;
+
+// Brightness of SDR white in HDR colour spaces.
+const float c_paper_white_nits = 200.0;
+
+// Column major.
+const mat3 c_rec709_to_rec2020 = mat3(
+	0.6274, 0.0691, 0.0164,
+	0.3293, 0.9195, 0.0880,
+	0.0433, 0.0114, 0.8956
+);
+
+const mat3 c_rec709_to_display_p3 = mat3(
+	0.8225, 0.0332, 0.0171,
+	0.1774, 0.9669, 0.0724,
+	0.0000, 0.0000, 0.9108
+);
+
+
+layout(buffer_reference, buffer_reference_align = 8, scalar) readonly buffer GlobalBufferPtr {
+	mat4 projection_view;
+	float time;
+	uint output_encoding;
+};
+
+layout(push_constant, std430) uniform constants {
+	GlobalBufferPtr u_global;
+};
 
 layout(location = 0) in vec3 v_color;
 
 layout(location = 0) out vec4 o_color;
 
+
+vec3 srgb_oetf(vec3 linear) {
+	vec3 low = linear * 12.92;
+	vec3 high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+	return mix(high, low, lessThanEqual(linear, vec3(0.0031308)));
+}
+
+// ST.2084 inverse EOTF. `nits` is absolute luminance, up to 10000.
+vec3 pq_oetf(vec3 nits) {
+	const float m1 = 0.1593017578125;
+	const float m2 = 78.84375;
+	const float c1 = 0.8359375;
+	const float c2 = 18.8515625;
+	const float c3 = 18.6875;
+
+	vec3 y = pow(clamp(nits / 10000.0, 0.0, 1.0), vec3(m1));
+	return pow((c1 + c2 * y) / (1.0 + c3 * y), vec3(m2));
+}
+
+// `linear` is linear Rec.709, with 1.0 being SDR white.
+vec3 encode_output(vec3 linear) {
+	switch (u_global.output_encoding) {
+		case OUTPUT_ENCODING_SCRGB: return linear * (c_paper_white_nits / 80.0);
+		case OUTPUT_ENCODING_HDR10: return pq_oetf(c_rec709_to_rec2020 * linear * c_paper_white_nits);
+		case OUTPUT_ENCODING_DISPLAY_P3: return srgb_oetf(max(c_rec709_to_display_p3 * linear, vec3(0.0)));
+		default: return linear;
+	}
+}
+
 void main() {
-	o_color = vec4(v_color, 1.0);
-} 
\ No newline at end of file
+	o_color = vec4(encode_output(v_color), 1.0);
+}
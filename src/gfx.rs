@@ -1,14 +1,32 @@
 pub mod core;
 pub mod debug;
+pub mod host_allocator;
+pub mod live_objects;
 
 pub mod allocator;
+pub mod buffer;
 pub mod deletion_queue;
+pub mod owned;
+pub mod usage_tracker;
+pub mod gpu_ptr;
+pub mod frame;
 pub mod presentable_surface;
+pub mod staging_buffer;
+pub mod uploader;
 
 pub use core::*;
 pub use allocator::*;
+pub use buffer::*;
 pub use debug::*;
+pub use host_allocator::*;
+pub use live_objects::*;
 pub use deletion_queue::*;
+pub use owned::*;
+pub use usage_tracker::*;
+pub use gpu_ptr::*;
+pub use frame::*;
 pub use presentable_surface::*;
+pub use staging_buffer::*;
+pub use uploader::*;
 
 
@@ -3,27 +3,148 @@ use ash::vk;
 
 use anyhow::Context;
 
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::panic::Location;
+use std::ptr::NonNull;
+
+mod block;
+mod defragment;
+mod statistics;
+
+pub use block::*;
+#[allow(unused_imports)]
+pub use defragment::*;
+#[allow(unused_imports)]
+pub use statistics::*;
+
 // https://gpuopen-librariesandsdks.github.io/VulkanMemoryAllocator/html/usage_patterns.html
 // https://www.gdcvault.com/play/1025458/Advanced-Graphics-Techniques-Tutorial-New
 
+/// Fraction of a heaps budget that can be used before [`MemoryBudgetPolicy::near_budget`] is called.
+pub const DEFAULT_BUDGET_WARNING_THRESHOLD: f32 = 0.9;
+
+/// Snapshot of how much of a memory heap is in use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapBudget {
+	pub heap_index: u32,
+
+	/// Total size of the heap.
+	pub size: u64,
+
+	/// How much of the heap this process can use before we start running into trouble.
+	/// Estimated from the heap size if VK_EXT_memory_budget isn't available.
+	pub budget: u64,
+
+	/// Estimated usage of the heap by this process, including allocations made since the last refresh.
+	pub usage: u64,
+
+	/// How much memory has been allocated from this heap through this allocator.
+	pub allocated_bytes: u64,
+}
+
+impl HeapBudget {
+	pub fn usage_ratio(&self) -> f32 {
+		if self.budget == 0 {
+			return 1.0;
+		}
+
+		self.usage as f32 / self.budget as f32
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverBudgetAction {
+	/// Fail the allocation.
+	Fail,
+
+	/// Allocate anyway, and let the driver page things out if it needs to.
+	#[allow(dead_code)]
+	Allow,
+}
+
+/// Lets the application react to memory pressure, e.g., by evicting or downscaling resources.
+pub trait MemoryBudgetPolicy {
+	/// Called when a heaps usage crosses the warning threshold.
+	/// Won't be called again for the same heap until usage drops back below the threshold.
+	fn near_budget(&mut self, _heap: &HeapBudget) {}
+
+	/// Called when an allocation would take a heap over its budget.
+	fn over_budget(&mut self, _heap: &HeapBudget, _requested_size: u64) -> OverBudgetAction {
+		OverBudgetAction::Fail
+	}
+}
+
+struct DefaultBudgetPolicy;
+
+impl MemoryBudgetPolicy for DefaultBudgetPolicy {}
+
+
+struct HeapState {
+	budget: HeapBudget,
+
+	// Usage reported by the driver at the last refresh, and how much we had allocated at that point.
+	// Usage between refreshes is estimated from the difference in allocated_bytes.
+	usage_at_refresh: u64,
+	allocated_bytes_at_refresh: u64,
+
+	near_budget: bool,
+}
+
+impl HeapState {
+	fn update_usage_estimate(&mut self) {
+		self.budget.usage = (self.usage_at_refresh + self.budget.allocated_bytes)
+			.saturating_sub(self.allocated_bytes_at_refresh);
+	}
+}
+
+/// Registry entry for a live allocation.
+#[derive(Debug, Clone)]
+pub struct AllocationInfo {
+	pub vk_memory: vk::DeviceMemory,
+	pub size: u64,
+	pub memory_type_index: u32,
+	pub heap_index: u32,
+
+	pub name: Option<String>,
+	pub location: &'static Location<'static>,
+
+	pub created_timeline_value: u64,
+	pub last_used_timeline_value: u64,
+
+	/// Order allocations were made in, since many can share a timeline value.
+	pub sequence_number: u64,
+}
+
+
 pub struct DeviceAllocator {
-	staging_memory_type_index: u32,
-	staging_memory_heap_budget: u64,
+	memory_props: vk::PhysicalDeviceMemoryProperties,
 
+	staging_memory_type_index: u32,
 	device_local_memory_type_index: u32,
-	device_local_memory_heap_budget: u64,
+	lazily_allocated_memory_type_index: Option<u32>,
 
-	buffer_alignment: u64,
-	image_alignment: u64,
-	rt_alignment: u64,
+	buffer_image_granularity: u64,
+
+	heaps: RefCell<Vec<HeapState>>,
+	allocations: RefCell<HashMap<vk::DeviceMemory, AllocationInfo>>,
+	next_sequence_number: Cell<u64>,
+
+	blocks: RefCell<Vec<MemoryBlock>>,
+	block_size: u64,
+
+	budget_warning_threshold: f32,
+	budget_policy: RefCell<Box<dyn MemoryBudgetPolicy>>,
 }
 
 impl DeviceAllocator {
 	pub fn new(core: &gfx::Core) -> anyhow::Result<DeviceAllocator> {
-		// TODO(pat.m): store
 		let mut memory_budgets = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
-		let mut memory_props = vk::PhysicalDeviceMemoryProperties2::default()
-			.push_next(&mut memory_budgets);
+		let mut memory_props = vk::PhysicalDeviceMemoryProperties2::default();
+
+		if core.supports_memory_budget {
+			memory_props = memory_props.push_next(&mut memory_budgets);
+		}
 
 		// Just everything we could possibly want from a buffer
 		let buffer_usage = vk::BufferUsageFlags::TRANSFER_SRC
@@ -63,15 +184,24 @@ impl DeviceAllocator {
 		let image_requirements_query = vk::DeviceImageMemoryRequirements::default()
 			.create_info(&image_create_info);
 
+		#[allow(clippy::clone_on_copy)]
 		let rt_create_info = image_create_info.clone()
 			.usage(rt_usage);
 
 		let rt_requirements_query = vk::DeviceImageMemoryRequirements::default()
 			.create_info(&rt_create_info);
 
+		// Attachments that are never loaded or stored, and so may never need to be backed by real memory
+		let transient_rt_create_info = image_create_info
+			.usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT);
+
+		let transient_rt_requirements_query = vk::DeviceImageMemoryRequirements::default()
+			.create_info(&transient_rt_create_info);
+
 		let mut buffer_requirements = vk::MemoryRequirements2::default();
 		let mut image_requirements = vk::MemoryRequirements2::default();
 		let mut rt_requirements = vk::MemoryRequirements2::default();
+		let mut transient_rt_requirements = vk::MemoryRequirements2::default();
 
 		// TODO(pat.m): max allocation count
 
@@ -80,25 +210,26 @@ impl DeviceAllocator {
 			core.vk_device.get_device_buffer_memory_requirements(&buffer_requirements_query, &mut buffer_requirements);
 			core.vk_device.get_device_image_memory_requirements(&image_requirements_query, &mut image_requirements);
 			core.vk_device.get_device_image_memory_requirements(&rt_requirements_query, &mut rt_requirements);
+			core.vk_device.get_device_image_memory_requirements(&transient_rt_requirements_query, &mut transient_rt_requirements);
 		};
 
 		let buffer_requirements = buffer_requirements.memory_requirements;
 		let image_requirements = image_requirements.memory_requirements;
 		let rt_requirements = rt_requirements.memory_requirements;
+		let transient_rt_requirements = transient_rt_requirements.memory_requirements;
 
 		let memory_props = memory_props.memory_properties;
+		let heap_budgets = query_heap_budgets(core, &memory_props, &memory_budgets);
 
 		log::info!("Available memory heaps:");
-		for heap_index in 0..memory_props.memory_heap_count as usize {
-			let heap = memory_props.memory_heaps[heap_index];
-
+		for (heap, &(budget, usage)) in memory_props.memory_heaps.iter().zip(&heap_budgets) {
 			let residency_str = match heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL) {
 				true => "Device",
 				false => "Host",
 			};
 
-			let budget = memory_budgets.heap_budget[heap_index] >> 20;
-			let usage = memory_budgets.heap_usage[heap_index] >> 20;
+			let budget = budget >> 20;
+			let usage = usage >> 20;
 			let total = heap.size >> 20;
 
 			log::info!("--- {residency_str:>6} Local: {budget:>5}MiB / {total:>5}MiB  (current usage: {usage}MiB)");
@@ -116,6 +247,8 @@ impl DeviceAllocator {
 		log::info!("Allowed rendertarget memory types: 0b{:b}", rt_requirements.memory_type_bits);
 		log::info!("Rendertarget alignment requirement: {}", rt_requirements.alignment);
 
+		log::info!("Allowed transient rendertarget memory types: 0b{:b}", transient_rt_requirements.memory_type_bits);
+
 		// Select the host local memory type with the largest associated heap for staging memory
 		let (staging_memory_type_index, selected_memory_type) = memory_props.memory_types.iter().enumerate()
 			.take(memory_props.memory_type_count as usize)
@@ -126,7 +259,7 @@ impl DeviceAllocator {
 				allows_buffers && has_desired_flags
 			})
 			.max_by_key(|(_, memory_type)| {
-				let mut budget = memory_budgets.heap_budget[memory_type.heap_index as usize];
+				let (mut budget, _) = heap_budgets[memory_type.heap_index as usize];
 
 				// Prefer uncached memory types
 				if !memory_type.property_flags.contains(vk::MemoryPropertyFlags::HOST_CACHED) {
@@ -139,7 +272,7 @@ impl DeviceAllocator {
 
 		let memory_heap_index = selected_memory_type.heap_index as usize;
 		let memory_heap = memory_props.memory_heaps[memory_heap_index];
-		let staging_memory_heap_budget = memory_budgets.heap_budget[memory_heap_index];
+		let (staging_memory_heap_budget, _) = heap_budgets[memory_heap_index];
 
 		log::info!("Selected Staging Memory Heap: {memory_heap:?} (#{memory_heap_index}) - budget: {staging_memory_heap_budget}", );
 		log::info!("Selected Staging Memory Type: {selected_memory_type:?} (#{staging_memory_type_index})");
@@ -154,33 +287,170 @@ impl DeviceAllocator {
 				let allows_rts = (1 << index) & rt_requirements.memory_type_bits != 0;
 				allows_buffers && allows_images && allows_rts && has_desired_flags
 			})
-			.max_by_key(|(_, memory_type)| memory_budgets.heap_budget[memory_type.heap_index as usize])
+			.max_by_key(|(_, memory_type)| heap_budgets[memory_type.heap_index as usize].0)
 			.context("Couldn't find device local memory type")?;
 
 		// TODO(pat.m): weight options to prefer non-host visible!
 
 		let memory_heap_index = selected_memory_type.heap_index as usize;
 		let memory_heap = memory_props.memory_heaps[memory_heap_index];
-		let device_local_memory_heap_budget = memory_budgets.heap_budget[memory_heap_index];
+		let (device_local_memory_heap_budget, _) = heap_budgets[memory_heap_index];
 
 		log::info!("Selected Device Local Memory Heap: {memory_heap:?} (#{memory_heap_index}) - budget: {device_local_memory_heap_budget}", );
 		log::info!("Selected Device Local Memory Type: {selected_memory_type:?} (#{device_local_memory_type_index})");
 
+		// Select a lazily allocated memory type for transient attachments if there is one.
+		// These are generally only available on tile-based GPUs, where attachments that are never loaded or stored can live
+		// entirely in tile memory.
+		let lazily_allocated_memory_type_index = memory_props.memory_types.iter().enumerate()
+			.take(memory_props.memory_type_count as usize)
+			.find(|(index, memory_type)| {
+				let has_desired_flags = memory_type.property_flags.contains(vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::LAZILY_ALLOCATED);
+				let allows_transient_rts = (1 << index) & transient_rt_requirements.memory_type_bits != 0;
+				allows_transient_rts && has_desired_flags
+			})
+			.map(|(index, _)| index as u32);
+
+		match lazily_allocated_memory_type_index {
+			Some(index) => log::info!("Selected Lazily Allocated Memory Type: {:?} (#{index})", memory_props.memory_types[index as usize]),
+			None => log::info!("No lazily allocated memory types available - transient attachments will use device local memory"),
+		}
+
+		let heaps = heap_budgets.iter().enumerate()
+			.map(|(heap_index, &(budget, usage))| HeapState {
+				budget: HeapBudget {
+					heap_index: heap_index as u32,
+					size: memory_props.memory_heaps[heap_index].size,
+					budget,
+					usage,
+					allocated_bytes: 0,
+				},
+
+				usage_at_refresh: usage,
+				allocated_bytes_at_refresh: 0,
+
+				near_budget: false,
+			})
+			.collect();
+
 		Ok(DeviceAllocator {
+			memory_props,
+
 			staging_memory_type_index: staging_memory_type_index as u32,
 			device_local_memory_type_index: device_local_memory_type_index as u32,
+			lazily_allocated_memory_type_index,
 
-			// TODO(pat.m): maybe storing these doesn't make sense?
-			staging_memory_heap_budget,
-			device_local_memory_heap_budget,
+			buffer_image_granularity: unsafe {
+				core.vk_instance.get_physical_device_properties(core.vk_physical_device).limits.buffer_image_granularity
+			},
 
-			buffer_alignment: buffer_requirements.alignment,
-			image_alignment: image_requirements.alignment,
-			rt_alignment: rt_requirements.alignment,
+			heaps: RefCell::new(heaps),
+			allocations: RefCell::new(HashMap::new()),
+			next_sequence_number: Cell::new(0),
+
+			blocks: RefCell::new(Vec::new()),
+			block_size: DEFAULT_BLOCK_SIZE,
+
+			budget_warning_threshold: DEFAULT_BUDGET_WARNING_THRESHOLD,
+			budget_policy: RefCell::new(Box::new(DefaultBudgetPolicy)),
 		})
 	}
 
-	fn allocate(core: &gfx::Core, size_bytes: u64, memory_type_index: u32) -> anyhow::Result<vk::DeviceMemory> {
+	#[allow(dead_code)]
+	pub fn set_budget_policy(&mut self, policy: impl MemoryBudgetPolicy + 'static) {
+		self.budget_policy = RefCell::new(Box::new(policy));
+	}
+
+	#[allow(dead_code)]
+	pub fn set_block_size(&mut self, block_size: u64) {
+		self.block_size = block_size;
+	}
+
+	#[allow(dead_code)]
+	pub fn set_budget_warning_threshold(&mut self, threshold: f32) {
+		self.budget_warning_threshold = threshold;
+	}
+
+	pub fn heap_budgets(&self) -> Vec<HeapBudget> {
+		self.heaps.borrow().iter()
+			.map(|heap| heap.budget)
+			.collect()
+	}
+
+	/// Requery heap budgets from the driver. Should be called once per frame.
+	pub fn update_budgets(&self, core: &gfx::Core) {
+		let mut memory_budgets = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+		let mut memory_props = vk::PhysicalDeviceMemoryProperties2::default();
+
+		if core.supports_memory_budget {
+			memory_props = memory_props.push_next(&mut memory_budgets);
+		}
+
+		unsafe {
+			core.vk_instance.get_physical_device_memory_properties2(core.vk_physical_device, &mut memory_props);
+		}
+
+		let memory_props = memory_props.memory_properties;
+		let heap_budgets = query_heap_budgets(core, &memory_props, &memory_budgets);
+
+		for (heap, &(budget, usage)) in self.heaps.borrow_mut().iter_mut().zip(&heap_budgets) {
+			heap.budget.budget = budget;
+
+			if core.supports_memory_budget {
+				heap.usage_at_refresh = usage;
+			} else {
+				heap.usage_at_refresh = heap.budget.allocated_bytes;
+			}
+
+			heap.allocated_bytes_at_refresh = heap.budget.allocated_bytes;
+			heap.update_usage_estimate();
+		}
+
+		for heap_index in 0..heap_budgets.len() {
+			self.check_budget_pressure(heap_index);
+		}
+	}
+
+	fn check_budget_pressure(&self, heap_index: usize) {
+		let heap_budget = {
+			let mut heaps = self.heaps.borrow_mut();
+			let heap = &mut heaps[heap_index];
+
+			let was_near_budget = heap.near_budget;
+			heap.near_budget = heap.budget.usage_ratio() >= self.budget_warning_threshold;
+
+			if !heap.near_budget || was_near_budget {
+				return;
+			}
+
+			heap.budget
+		};
+
+		log::warn!("Memory heap #{heap_index} is nearing its budget: {}MiB / {}MiB", heap_budget.usage >> 20, heap_budget.budget >> 20);
+
+		self.budget_policy.borrow_mut().near_budget(&heap_budget);
+	}
+
+	#[track_caller]
+	fn allocate(&self, core: &gfx::Core, size_bytes: u64, memory_type_index: u32) -> anyhow::Result<vk::DeviceMemory> {
+		let location = Location::caller();
+
+		let heap_index = self.memory_props.memory_types[memory_type_index as usize].heap_index as usize;
+		let heap_budget = self.heaps.borrow()[heap_index].budget;
+
+		anyhow::ensure!(size_bytes <= heap_budget.size, "Memory heap #{heap_index} not big enough for allocation of {size_bytes} bytes :(");
+
+		if heap_budget.usage + size_bytes > heap_budget.budget {
+			let action = self.budget_policy.borrow_mut().over_budget(&heap_budget, size_bytes);
+
+			match action {
+				OverBudgetAction::Fail => anyhow::bail!("Allocation of {size_bytes} bytes would exceed budget of memory heap #{heap_index}: {}MiB / {}MiB",
+					heap_budget.usage >> 20, heap_budget.budget >> 20),
+
+				OverBudgetAction::Allow => log::warn!("Allocation of {size_bytes} bytes exceeds budget of memory heap #{heap_index}"),
+			}
+		}
+
 		let mut allocate_flags = vk::MemoryAllocateFlagsInfo::default()
 			.flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
 
@@ -190,19 +460,247 @@ impl DeviceAllocator {
 			.push_next(&mut allocate_flags);
 
 		let vk_memory = unsafe {
-			core.vk_device.allocate_memory(&allocate_info, None)?
+			core.vk_device.allocate_memory(&allocate_info, core.allocation_callbacks())?
 		};
 
+		{
+			let heap = &mut self.heaps.borrow_mut()[heap_index];
+			heap.budget.allocated_bytes += size_bytes;
+			heap.update_usage_estimate();
+		}
+
+		let timeline_value = core.timeline_value.get();
+
+		let sequence_number = self.next_sequence_number.get();
+		self.next_sequence_number.set(sequence_number + 1);
+
+		self.allocations.borrow_mut().insert(vk_memory, AllocationInfo {
+			vk_memory,
+			size: size_bytes,
+			memory_type_index,
+			heap_index: heap_index as u32,
+
+			name: None,
+			location,
+
+			created_timeline_value: timeline_value,
+			last_used_timeline_value: timeline_value,
+
+			sequence_number,
+		});
+
+		self.check_budget_pressure(heap_index);
+
 		Ok(vk_memory)
 	}
 
+	/// Free memory allocated through this allocator.
+	/// Should generally be called through [`gfx::DeletionQueue`] rather than directly.
+	pub unsafe fn free(&self, core: &gfx::Core, vk_memory: vk::DeviceMemory) {
+		unsafe {
+			core.vk_device.free_memory(vk_memory, core.allocation_callbacks());
+		}
+
+		let Some(allocation) = self.allocations.borrow_mut().remove(&vk_memory) else {
+			log::warn!("Freeing memory {vk_memory:?} that wasn't allocated by DeviceAllocator");
+			return
+		};
+
+		let heap = &mut self.heaps.borrow_mut()[allocation.heap_index as usize];
+		heap.budget.allocated_bytes -= allocation.size;
+		heap.update_usage_estimate();
+
+		if heap.budget.usage_ratio() < self.budget_warning_threshold {
+			heap.near_budget = false;
+		}
+	}
+
+	#[track_caller]
 	pub fn allocate_staging_memory(&self, core: &gfx::Core, size_bytes: u64) -> anyhow::Result<vk::DeviceMemory> {
-		anyhow::ensure!(size_bytes <= self.staging_memory_heap_budget, "Staging memory heap not big enough :(");
-		Self::allocate(core, size_bytes, self.staging_memory_type_index)
+		self.allocate(core, size_bytes, self.staging_memory_type_index)
 	}
 
+	#[track_caller]
+	#[allow(dead_code)]
 	pub fn allocate_device_memory(&self, core: &gfx::Core, size_bytes: u64) -> anyhow::Result<vk::DeviceMemory> {
-		anyhow::ensure!(size_bytes <= self.device_local_memory_heap_budget, "Device local memory heap not big enough :(");
-		Self::allocate(core, size_bytes, self.device_local_memory_type_index)
+		self.allocate(core, size_bytes, self.device_local_memory_type_index)
 	}
-}
\ No newline at end of file
+
+	fn memory_type_index_for(&self, location: MemoryLocation) -> u32 {
+		match location {
+			MemoryLocation::DeviceLocal => self.device_local_memory_type_index,
+			MemoryLocation::Staging => self.staging_memory_type_index,
+		}
+	}
+
+	/// Allocate a range of memory from a shared block.
+	/// Allocations larger than half the block size get a block to themselves.
+	#[track_caller]
+	pub fn sub_allocate(&self, core: &gfx::Core, requirements: &vk::MemoryRequirements, location: MemoryLocation, tiling: ResourceTiling) -> anyhow::Result<SubAllocation> {
+		let memory_type_index = self.memory_type_index_for(location);
+
+		// Padding optimal resources out to whole pages means they can never share one with a linear resource,
+		// without linear resources having to pay for it.
+		let mut requirements = *requirements;
+		if tiling == ResourceTiling::Optimal {
+			requirements.alignment = requirements.alignment.max(self.buffer_image_granularity);
+			requirements.size = requirements.size.next_multiple_of(self.buffer_image_granularity);
+		}
+
+		anyhow::ensure!((1 << memory_type_index) & requirements.memory_type_bits != 0,
+			"Resource can't be allocated from {location:?} memory (allowed types: 0b{:b})", requirements.memory_type_bits);
+
+		let dedicated = requirements.size > self.block_size / 2;
+
+		if !dedicated {
+			let mut blocks = self.blocks.borrow_mut();
+			let found_range = blocks.iter().enumerate()
+				.filter(|(_, block)| block.memory_type_index == memory_type_index)
+				.find_map(|(block_index, block)| {
+					block.find_range(requirements.size, requirements.alignment)
+						.map(|offset| (block_index, offset))
+				});
+
+			if let Some((block_index, offset)) = found_range {
+				return Ok(blocks[block_index].allocate_range(offset, requirements.size));
+			}
+		}
+
+		let block_size = match dedicated {
+			true => requirements.size,
+			false => self.block_size,
+		};
+
+		let vk_memory = self.allocate(core, block_size, memory_type_index)?;
+		self.set_allocation_name(vk_memory, format!("{location:?} Memory Block"));
+
+		let mapped_ptr = match location {
+			MemoryLocation::Staging => unsafe {
+				let ptr = core.vk_device.map_memory(vk_memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?;
+				NonNull::new(ptr.cast())
+			}
+
+			MemoryLocation::DeviceLocal => None,
+		};
+
+		let mut block = MemoryBlock::new(vk_memory, memory_type_index, block_size, mapped_ptr);
+		let allocation = block.allocate_range(0, requirements.size);
+		self.blocks.borrow_mut().push(block);
+
+		Ok(allocation)
+	}
+
+	/// Return a sub-allocation to its block. The block is freed once nothing else is allocated from it.
+	/// Should generally be called through [`gfx::DeletionQueue`] rather than directly.
+	pub unsafe fn free_sub_allocation(&self, core: &gfx::Core, allocation: &SubAllocation) {
+		let mut blocks = self.blocks.borrow_mut();
+
+		let Some(block_index) = blocks.iter().position(|block| block.vk_memory == allocation.vk_memory) else {
+			log::warn!("Freeing sub-allocation {allocation:?} from unknown memory block");
+			return
+		};
+
+		let block = &mut blocks[block_index];
+		block.free_range(allocation.offset, allocation.size);
+
+		if block.is_empty() {
+			let block = blocks.swap_remove(block_index);
+			drop(blocks);
+
+			unsafe {
+				self.free(core, block.vk_memory);
+			}
+		}
+	}
+
+	#[allow(dead_code)]
+	pub fn supports_lazily_allocated_memory(&self) -> bool {
+		self.lazily_allocated_memory_type_index.is_some()
+	}
+
+	/// Allocate memory for an image created with `TRANSIENT_ATTACHMENT` usage, i.e., an attachment whose contents
+	/// are never loaded or stored.
+	/// Uses lazily allocated memory where available, and falls back to device local memory where it isn't.
+	#[track_caller]
+	pub fn allocate_transient_attachment_memory(&self, core: &gfx::Core, requirements: &vk::MemoryRequirements) -> anyhow::Result<vk::DeviceMemory> {
+		let allows_memory_type = |index: u32| (1 << index) & requirements.memory_type_bits != 0;
+
+		if let Some(memory_type_index) = self.lazily_allocated_memory_type_index {
+			if allows_memory_type(memory_type_index) {
+				return self.allocate(core, requirements.size, memory_type_index);
+			}
+		}
+
+		anyhow::ensure!(allows_memory_type(self.device_local_memory_type_index),
+			"Transient attachment can't be allocated from device local memory type (allowed types: 0b{:b})", requirements.memory_type_bits);
+
+		self.allocate(core, requirements.size, self.device_local_memory_type_index)
+	}
+
+	pub fn set_allocation_name(&self, vk_memory: vk::DeviceMemory, name: impl Into<String>) {
+		if let Some(allocation) = self.allocations.borrow_mut().get_mut(&vk_memory) {
+			allocation.name = Some(name.into());
+		}
+	}
+
+	/// Record that `vk_memory` is used by the submission that will signal `timeline_value`.
+	pub fn mark_used(&self, vk_memory: vk::DeviceMemory, timeline_value: u64) {
+		if let Some(allocation) = self.allocations.borrow_mut().get_mut(&vk_memory) {
+			allocation.last_used_timeline_value = allocation.last_used_timeline_value.max(timeline_value);
+		}
+	}
+
+	#[allow(dead_code)]
+	pub fn allocation_info(&self, vk_memory: vk::DeviceMemory) -> Option<AllocationInfo> {
+		self.allocations.borrow().get(&vk_memory).cloned()
+	}
+
+	/// All live allocations, oldest first.
+	pub fn allocations(&self) -> Vec<AllocationInfo> {
+		let mut allocations: Vec<_> = self.allocations.borrow().values().cloned().collect();
+		allocations.sort_by_key(|allocation| allocation.sequence_number);
+		allocations
+	}
+
+	/// Log any allocations that are still alive - i.e., were never freed through [`gfx::DeletionQueue`].
+	/// Returns the number of leaked allocations.
+	pub fn report_leaks(&self) -> usize {
+		let allocations = self.allocations();
+		if allocations.is_empty() {
+			return 0;
+		}
+
+		let leaked_bytes: u64 = allocations.iter().map(|allocation| allocation.size).sum();
+		log::error!("{} device memory allocations ({}KiB) were never freed:", allocations.len(), leaked_bytes >> 10);
+
+		for allocation in allocations.iter() {
+			let name = allocation.name.as_deref().unwrap_or("<unnamed>");
+			log::error!("--- '{name}' {:?}: {}KiB of memory type #{}, allocated at {} (timeline value {}, last used {})",
+				allocation.vk_memory, allocation.size >> 10, allocation.memory_type_index,
+				allocation.location, allocation.created_timeline_value, allocation.last_used_timeline_value);
+		}
+
+		allocations.len()
+	}
+}
+
+impl Drop for DeviceAllocator {
+	fn drop(&mut self) {
+		self.report_leaks();
+	}
+}
+
+
+/// Returns (budget, usage) for each heap.
+/// If VK_EXT_memory_budget isn't supported then budget is estimated as 80% of the heap size, and usage will be zero.
+fn query_heap_budgets(core: &gfx::Core, memory_props: &vk::PhysicalDeviceMemoryProperties, memory_budgets: &vk::PhysicalDeviceMemoryBudgetPropertiesEXT) -> Vec<(u64, u64)> {
+	(0..memory_props.memory_heap_count as usize)
+		.map(|heap_index| {
+			if core.supports_memory_budget {
+				(memory_budgets.heap_budget[heap_index], memory_budgets.heap_usage[heap_index])
+			} else {
+				(memory_props.memory_heaps[heap_index].size * 8 / 10, 0)
+			}
+		})
+		.collect()
+}
@@ -0,0 +1,164 @@
+use ash::vk;
+
+use std::ptr::NonNull;
+
+
+/// Default size of memory blocks that smaller allocations are sub-allocated from.
+pub const DEFAULT_BLOCK_SIZE: u64 = 64 << 20;
+
+
+/// Where a sub-allocation should live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryLocation {
+	/// Fast GPU memory, not necessarily visible to the host.
+	DeviceLocal,
+
+	/// Host visible, persistently mapped memory - for uploads and data written by the CPU every frame.
+	#[allow(dead_code)]
+	Staging,
+}
+
+
+/// How the resource bound to a sub-allocation is laid out in memory.
+/// Linear and optimal resources can't share a `bufferImageGranularity` sized page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceTiling {
+	/// Buffers and linearly tiled images.
+	Linear,
+
+	/// Optimally tiled images.
+	Optimal,
+}
+
+
+/// A range of memory within a [`MemoryBlock`].
+#[derive(Debug, Clone)]
+pub struct SubAllocation {
+	pub vk_memory: vk::DeviceMemory,
+	pub memory_type_index: u32,
+
+	pub offset: u64,
+	pub size: u64,
+
+	/// Pointer to the start of this range if the memory it lives in is host visible.
+	pub mapped_ptr: Option<NonNull<u8>>,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct FreeRange {
+	pub offset: u64,
+	pub size: u64,
+}
+
+pub(super) struct MemoryBlock {
+	pub vk_memory: vk::DeviceMemory,
+	pub memory_type_index: u32,
+	pub size: u64,
+
+	pub mapped_ptr: Option<NonNull<u8>>,
+
+	/// Sorted by offset, and never adjacent - neighbouring ranges are merged on free.
+	pub free_ranges: Vec<FreeRange>,
+	pub used_bytes: u64,
+	pub sub_allocation_count: usize,
+}
+
+impl MemoryBlock {
+	pub fn new(vk_memory: vk::DeviceMemory, memory_type_index: u32, size: u64, mapped_ptr: Option<NonNull<u8>>) -> MemoryBlock {
+		MemoryBlock {
+			vk_memory,
+			memory_type_index,
+			size,
+
+			mapped_ptr,
+
+			free_ranges: vec![FreeRange { offset: 0, size }],
+			used_bytes: 0,
+			sub_allocation_count: 0,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.sub_allocation_count == 0
+	}
+
+	/// Find the lowest offset that `size` bytes could be allocated at.
+	pub fn find_range(&self, size: u64, alignment: u64) -> Option<u64> {
+		self.free_ranges.iter()
+			.find_map(|range| {
+				let aligned_offset = range.offset.next_multiple_of(alignment);
+				let padding = aligned_offset - range.offset;
+				(range.size >= size + padding).then_some(aligned_offset)
+			})
+	}
+
+	/// Mark `size` bytes at `offset` as used. `offset` must have been returned by `find_range`.
+	pub fn allocate_range(&mut self, offset: u64, size: u64) -> SubAllocation {
+		let range_index = self.free_ranges.iter()
+			.position(|range| range.offset <= offset && offset + size <= range.offset + range.size)
+			.expect("Allocating range that isn't free");
+
+		let range = self.free_ranges.remove(range_index);
+
+		// Split off whatever is left either side of the new allocation.
+		let after = FreeRange { offset: offset + size, size: range.offset + range.size - (offset + size) };
+		if after.size > 0 {
+			self.free_ranges.insert(range_index, after);
+		}
+
+		let before = FreeRange { offset: range.offset, size: offset - range.offset };
+		if before.size > 0 {
+			self.free_ranges.insert(range_index, before);
+		}
+
+		self.used_bytes += size;
+		self.sub_allocation_count += 1;
+
+		SubAllocation {
+			vk_memory: self.vk_memory,
+			memory_type_index: self.memory_type_index,
+
+			offset,
+			size,
+
+			mapped_ptr: self.mapped_ptr.map(|ptr| unsafe { ptr.add(offset as usize) }),
+		}
+	}
+
+	pub fn free_range(&mut self, offset: u64, size: u64) {
+		let index = self.free_ranges.partition_point(|range| range.offset < offset);
+		self.free_ranges.insert(index, FreeRange { offset, size });
+
+		// Merge with next
+		if let Some(next) = self.free_ranges.get(index + 1).copied() {
+			if offset + size == next.offset {
+				self.free_ranges[index].size += next.size;
+				self.free_ranges.remove(index + 1);
+			}
+		}
+
+		// Merge with previous
+		if index > 0 {
+			let previous = self.free_ranges[index - 1];
+			if previous.offset + previous.size == offset {
+				self.free_ranges[index - 1].size += self.free_ranges[index].size;
+				self.free_ranges.remove(index);
+			}
+		}
+
+		self.used_bytes -= size;
+		self.sub_allocation_count -= 1;
+	}
+
+	pub fn free_bytes(&self) -> u64 {
+		self.size - self.used_bytes
+	}
+
+	pub fn largest_free_range(&self) -> u64 {
+		self.free_ranges.iter()
+			.map(|range| range.size)
+			.max()
+			.unwrap_or(0)
+	}
+}
@@ -0,0 +1,361 @@
+use super::*;
+
+
+/// Index into a [`MovableBuffers`] table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(dead_code)]
+pub struct MovableBufferId(u32);
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct MovableBuffer {
+	pub vk_buffer: vk::Buffer,
+	pub device_address: vk::DeviceAddress,
+	pub allocation: SubAllocation,
+
+	pub size: u64,
+	pub usage: vk::BufferUsageFlags,
+	alignment: u64,
+}
+
+/// Indirection table for buffers that the defragmenter is allowed to move.
+/// Buffer handles and device addresses may change between frames, so users should look them up
+/// through here each frame rather than holding on to them.
+#[derive(Default)]
+#[allow(dead_code)]
+pub struct MovableBuffers {
+	entries: Vec<Option<MovableBuffer>>,
+	free_ids: Vec<u32>,
+}
+
+#[allow(dead_code)]
+impl MovableBuffers {
+	pub fn create_buffer(&mut self, core: &gfx::Core, allocator: &DeviceAllocator, size: u64, usage: vk::BufferUsageFlags, location: MemoryLocation) -> anyhow::Result<MovableBufferId> {
+		// Moving a buffer means copying out of and into it.
+		let usage = usage
+			| vk::BufferUsageFlags::TRANSFER_SRC
+			| vk::BufferUsageFlags::TRANSFER_DST
+			| vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+
+		let vk_buffer = create_buffer(core, size, usage)?;
+		let requirements = unsafe { core.vk_device.get_buffer_memory_requirements(vk_buffer) };
+
+		let allocation = match allocator.sub_allocate(core, &requirements, location, gfx::ResourceTiling::Linear) {
+			Ok(allocation) => allocation,
+			Err(error) => {
+				unsafe { core.vk_device.destroy_buffer(vk_buffer, core.allocation_callbacks()); }
+				return Err(error);
+			}
+		};
+
+		let device_address = match unsafe { bind_buffer(core, vk_buffer, &allocation) } {
+			Ok(device_address) => device_address,
+			Err(error) => {
+				unsafe {
+					core.vk_device.destroy_buffer(vk_buffer, core.allocation_callbacks());
+					allocator.free_sub_allocation(core, &allocation);
+				}
+
+				return Err(error);
+			}
+		};
+
+		core.register_object(vk_buffer, "Movable Buffer");
+
+		let buffer = MovableBuffer {
+			vk_buffer,
+			device_address,
+			allocation,
+
+			size,
+			usage,
+			alignment: requirements.alignment,
+		};
+
+		let id = match self.free_ids.pop() {
+			Some(id) => {
+				self.entries[id as usize] = Some(buffer);
+				id
+			}
+
+			None => {
+				self.entries.push(Some(buffer));
+				self.entries.len() as u32 - 1
+			}
+		};
+
+		Ok(MovableBufferId(id))
+	}
+
+	pub fn get(&self, id: MovableBufferId) -> &MovableBuffer {
+		self.entries[id.0 as usize].as_ref()
+			.expect("Use of deleted MovableBufferId")
+	}
+
+	pub fn device_address(&self, id: MovableBufferId) -> vk::DeviceAddress {
+		self.get(id).device_address
+	}
+
+	pub fn queue_deletion(&mut self, id: MovableBufferId, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue) {
+		let buffer = self.entries[id.0 as usize].take()
+			.expect("Double deletion of MovableBufferId");
+
+		self.free_ids.push(id.0);
+
+		let timeline_value = core.timeline_value.get();
+		deletion_queue.queue_deletion_after(buffer.vk_buffer, timeline_value);
+		deletion_queue.queue_deletion_after(buffer.allocation, timeline_value);
+	}
+}
+
+
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(dead_code)]
+pub struct DefragmentationStats {
+	pub bytes_moved: u64,
+	pub buffers_moved: usize,
+}
+
+impl DeviceAllocator {
+	/// Run one incremental defragmentation pass, moving at most `max_bytes` worth of buffers.
+	///
+	/// Buffers are moved out of the emptiest blocks and into fuller blocks (or lower down in the same block),
+	/// so that emptied blocks can be freed. No new blocks are allocated to make room.
+	///
+	/// Copies are recorded into `vk_cmd_buffer`, which must be submitted with the next timeline value.
+	/// Old buffers and memory ranges are retired through `deletion_queue` once that submission completes.
+	/// Failing to create a buffer just ends the pass early.
+	#[allow(dead_code)]
+	pub fn defragment(&self, core: &gfx::Core, vk_cmd_buffer: vk::CommandBuffer, buffers: &mut MovableBuffers,
+		deletion_queue: &mut gfx::DeletionQueue, max_bytes: u64) -> DefragmentationStats
+	{
+		let mut stats = DefragmentationStats::default();
+
+		let candidates = defragment_candidates(&self.blocks.borrow(), &buffers.entries);
+
+		let retire_timeline_value = core.timeline_value.get() + 1;
+
+		for id in candidates {
+			let entry = buffers.entries[id as usize].as_mut().unwrap();
+
+			// Smaller buffers further down may still fit in what's left of the budget.
+			if stats.bytes_moved + entry.size > max_bytes {
+				continue;
+			}
+
+			let Some(destination) = self.allocate_defragment_destination(&entry.allocation, entry.alignment) else {
+				continue
+			};
+
+			let (vk_buffer, device_address) = match create_bound_buffer(core, entry.size, entry.usage, &destination) {
+				Ok(new_buffer) => new_buffer,
+				Err(error) => {
+					log::error!("Failed to create buffer while defragmenting: {error}");
+					unsafe { self.free_sub_allocation(core, &destination); }
+					break;
+				}
+			};
+
+			if stats.buffers_moved == 0 {
+				unsafe {
+					// Any previous writes to the buffers being moved must complete before they can be copied.
+					core.vk_device.cmd_pipeline_barrier2(
+						vk_cmd_buffer,
+						&vk::DependencyInfo::default()
+							.memory_barriers(&[
+								vk::MemoryBarrier2::default()
+									.src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+									.src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+									.dst_stage_mask(vk::PipelineStageFlags2::COPY)
+									.dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+							])
+					);
+				}
+			}
+
+			core.register_object(vk_buffer, "Movable Buffer");
+
+			unsafe {
+				core.vk_device.cmd_copy_buffer(vk_cmd_buffer, entry.vk_buffer, vk_buffer, &[
+					vk::BufferCopy::default().size(entry.size)
+				]);
+			}
+
+			let old_buffer = std::mem::replace(&mut entry.vk_buffer, vk_buffer);
+			let old_allocation = std::mem::replace(&mut entry.allocation, destination);
+			entry.device_address = device_address;
+
+			deletion_queue.queue_deletion_after(old_buffer, retire_timeline_value);
+			deletion_queue.queue_deletion_after(old_allocation, retire_timeline_value);
+
+			stats.bytes_moved += entry.size;
+			stats.buffers_moved += 1;
+		}
+
+		if stats.buffers_moved == 0 {
+			return stats;
+		}
+
+		unsafe {
+			// Moved buffers must be fully written before anything else in the frame touches them.
+			core.vk_device.cmd_pipeline_barrier2(
+				vk_cmd_buffer,
+				&vk::DependencyInfo::default()
+					.memory_barriers(&[
+						vk::MemoryBarrier2::default()
+							.src_stage_mask(vk::PipelineStageFlags2::COPY)
+							.src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+							.dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+							.dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+					])
+			);
+		}
+
+		log::debug!("Defragmentation moved {} buffers ({}KiB)", stats.buffers_moved, stats.bytes_moved >> 10);
+
+		stats
+	}
+
+	/// Find a better home for `allocation` - either in a fuller block of the same memory type, or lower down in its own block.
+	fn allocate_defragment_destination(&self, allocation: &SubAllocation, alignment: u64) -> Option<SubAllocation> {
+		let mut blocks = self.blocks.borrow_mut();
+		let (block_index, offset) = find_defragment_destination(&blocks, allocation, alignment)?;
+		Some(blocks[block_index].allocate_range(offset, allocation.size))
+	}
+}
+
+
+/// Ids of buffers in the order they should be moved - emptiest blocks first, and the end of each block first.
+fn defragment_candidates(blocks: &[MemoryBlock], entries: &[Option<MovableBuffer>]) -> Vec<u32> {
+	let mut candidates: Vec<(u32, u64, u64)> = entries.iter().enumerate()
+		.filter_map(|(id, entry)| {
+			let entry = entry.as_ref()?;
+			let block = blocks.iter().find(|block| block.vk_memory == entry.allocation.vk_memory)?;
+			Some((id as u32, block.used_bytes, entry.allocation.offset))
+		})
+		.collect();
+
+	candidates.sort_by_key(|&(_, block_used_bytes, offset)| (block_used_bytes, std::cmp::Reverse(offset)));
+	candidates.into_iter().map(|(id, _, _)| id).collect()
+}
+
+/// Block index and offset that `allocation` should be moved to, if anywhere is better than where it is.
+fn find_defragment_destination(blocks: &[MemoryBlock], allocation: &SubAllocation, alignment: u64) -> Option<(usize, u64)> {
+	let source_used_bytes = blocks.iter()
+		.find(|block| block.vk_memory == allocation.vk_memory)?
+		.used_bytes;
+
+	blocks.iter().enumerate()
+		.filter(|(_, block)| block.memory_type_index == allocation.memory_type_index)
+		.filter_map(|(block_index, block)| {
+			let offset = block.find_range(allocation.size, alignment)?;
+
+			let is_better = match block.vk_memory == allocation.vk_memory {
+				true => offset + allocation.size <= allocation.offset,
+				false => block.used_bytes > source_used_bytes,
+			};
+
+			is_better.then_some((block_index, offset))
+		})
+		.max_by_key(|&(block_index, _)| blocks[block_index].used_bytes)
+}
+
+fn create_buffer(core: &gfx::Core, size: u64, usage: vk::BufferUsageFlags) -> anyhow::Result<vk::Buffer> {
+	let buffer_info = vk::BufferCreateInfo::default()
+		.size(size)
+		.usage(usage)
+		.sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+	unsafe {
+		core.vk_device.create_buffer(&buffer_info, core.allocation_callbacks())
+			.map_err(Into::into)
+	}
+}
+
+fn create_bound_buffer(core: &gfx::Core, size: u64, usage: vk::BufferUsageFlags, allocation: &SubAllocation) -> anyhow::Result<(vk::Buffer, vk::DeviceAddress)> {
+	let vk_buffer = create_buffer(core, size, usage)?;
+
+	match unsafe { bind_buffer(core, vk_buffer, allocation) } {
+		Ok(device_address) => Ok((vk_buffer, device_address)),
+		Err(error) => {
+			unsafe { core.vk_device.destroy_buffer(vk_buffer, core.allocation_callbacks()); }
+			Err(error)
+		}
+	}
+}
+
+unsafe fn bind_buffer(core: &gfx::Core, vk_buffer: vk::Buffer, allocation: &SubAllocation) -> anyhow::Result<vk::DeviceAddress> {
+	unsafe {
+		core.vk_device.bind_buffer_memory(vk_buffer, allocation.vk_memory, allocation.offset)?;
+		Ok(core.vk_device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(vk_buffer)))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ash::vk::Handle;
+
+	fn block(raw_memory: u64, memory_type_index: u32) -> MemoryBlock {
+		MemoryBlock::new(vk::DeviceMemory::from_raw(raw_memory), memory_type_index, 100, None)
+	}
+
+	fn movable(allocation: SubAllocation) -> Option<MovableBuffer> {
+		Some(MovableBuffer {
+			vk_buffer: vk::Buffer::null(),
+			device_address: 0,
+			size: allocation.size,
+			allocation,
+
+			usage: vk::BufferUsageFlags::empty(),
+			alignment: 1,
+		})
+	}
+
+	#[test]
+	fn moves_into_fuller_block() {
+		let mut blocks = vec![block(1, 0), block(2, 0)];
+
+		let allocation = blocks[0].allocate_range(50, 10);
+		blocks[1].allocate_range(0, 50);
+
+		assert_eq!(find_defragment_destination(&blocks, &allocation, 1), Some((1, 50)));
+	}
+
+	#[test]
+	fn compacts_within_block_when_no_fuller_block() {
+		let mut blocks = vec![block(1, 0), block(2, 0)];
+
+		let allocation = blocks[0].allocate_range(50, 10);
+		blocks[0].allocate_range(90, 10);
+		blocks[1].allocate_range(0, 10);
+
+		assert_eq!(find_defragment_destination(&blocks, &allocation, 1), Some((0, 0)));
+	}
+
+	#[test]
+	fn ignores_other_memory_types_and_worse_positions() {
+		let mut blocks = vec![block(1, 0), block(2, 1)];
+
+		let allocation = blocks[0].allocate_range(0, 10);
+		blocks[1].allocate_range(0, 50);
+
+		assert_eq!(find_defragment_destination(&blocks, &allocation, 1), None);
+	}
+
+	#[test]
+	fn candidates_are_emptiest_block_and_highest_offset_first() {
+		let mut blocks = vec![block(1, 0), block(2, 0)];
+
+		let entries = vec![
+			movable(blocks[0].allocate_range(0, 10)),
+			movable(blocks[1].allocate_range(0, 5)),
+			None,
+			movable(blocks[0].allocate_range(20, 10)),
+			movable(blocks[1].allocate_range(40, 5)),
+		];
+
+		assert_eq!(defragment_candidates(&blocks, &entries), [4, 1, 3, 0]);
+	}
+}
@@ -0,0 +1,308 @@
+use super::*;
+
+use std::fmt::Write;
+
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStatistics {
+	/// Number of VkDeviceMemory objects, including memory blocks.
+	pub allocation_count: usize,
+	pub allocated_bytes: u64,
+
+	pub sub_allocation_count: usize,
+
+	/// Bytes within allocations that aren't currently in use by any resource.
+	pub free_bytes: u64,
+	pub free_range_count: usize,
+	pub largest_free_range: u64,
+}
+
+impl MemoryStatistics {
+	/// 0 if all free space is in one contiguous range, approaching 1 as free space gets split into many small ranges.
+	pub fn fragmentation(&self) -> f32 {
+		if self.free_bytes == 0 {
+			return 0.0;
+		}
+
+		1.0 - self.largest_free_range as f32 / self.free_bytes as f32
+	}
+
+	fn add_allocation(&mut self, allocation: &AllocationInfo) {
+		self.allocation_count += 1;
+		self.allocated_bytes += allocation.size;
+	}
+
+	fn add_block(&mut self, block: &MemoryBlock) {
+		self.sub_allocation_count += block.sub_allocation_count;
+		self.free_bytes += block.free_bytes();
+		self.free_range_count += block.free_ranges.len();
+		self.largest_free_range = self.largest_free_range.max(block.largest_free_range());
+	}
+
+	fn merge(&mut self, other: &MemoryStatistics) {
+		self.allocation_count += other.allocation_count;
+		self.allocated_bytes += other.allocated_bytes;
+		self.sub_allocation_count += other.sub_allocation_count;
+		self.free_bytes += other.free_bytes;
+		self.free_range_count += other.free_range_count;
+		self.largest_free_range = self.largest_free_range.max(other.largest_free_range);
+	}
+}
+
+
+#[derive(Debug, Clone)]
+pub struct HeapStatistics {
+	pub budget: HeapBudget,
+	pub flags: vk::MemoryHeapFlags,
+	pub stats: MemoryStatistics,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryTypeStatistics {
+	pub memory_type_index: u32,
+	pub heap_index: u32,
+	pub property_flags: vk::MemoryPropertyFlags,
+	pub stats: MemoryStatistics,
+}
+
+#[derive(Debug, Clone)]
+pub struct AllocatorStatistics {
+	pub total: MemoryStatistics,
+	pub heaps: Vec<HeapStatistics>,
+	pub memory_types: Vec<MemoryTypeStatistics>,
+}
+
+
+impl DeviceAllocator {
+	pub fn statistics(&self) -> AllocatorStatistics {
+		let memory_props = &self.memory_props;
+
+		let mut memory_types: Vec<_> = memory_props.memory_types.iter()
+			.take(memory_props.memory_type_count as usize)
+			.enumerate()
+			.map(|(memory_type_index, memory_type)| MemoryTypeStatistics {
+				memory_type_index: memory_type_index as u32,
+				heap_index: memory_type.heap_index,
+				property_flags: memory_type.property_flags,
+				stats: MemoryStatistics::default(),
+			})
+			.collect();
+
+		for allocation in self.allocations.borrow().values() {
+			memory_types[allocation.memory_type_index as usize].stats.add_allocation(allocation);
+		}
+
+		for block in self.blocks.borrow().iter() {
+			memory_types[block.memory_type_index as usize].stats.add_block(block);
+		}
+
+		let mut heaps: Vec<_> = self.heap_budgets().into_iter()
+			.map(|budget| HeapStatistics {
+				budget,
+				flags: memory_props.memory_heaps[budget.heap_index as usize].flags,
+				stats: MemoryStatistics::default(),
+			})
+			.collect();
+
+		let mut total = MemoryStatistics::default();
+
+		for memory_type in memory_types.iter() {
+			heaps[memory_type.heap_index as usize].stats.merge(&memory_type.stats);
+			total.merge(&memory_type.stats);
+		}
+
+		AllocatorStatistics {
+			total,
+			heaps,
+			memory_types,
+		}
+	}
+
+	/// Dump allocator statistics as JSON, in roughly the same shape as VMA's vmaBuildStatsString.
+	/// If `detailed` is set, every live allocation is listed under the memory type it was allocated from.
+	pub fn build_stats_json(&self, detailed: bool) -> String {
+		let statistics = self.statistics();
+		let allocations = match detailed {
+			true => self.allocations(),
+			false => Vec::new(),
+		};
+
+		let mut json = String::new();
+
+		// Writing to a String can't fail.
+		let _ = write_stats_json(&mut json, &statistics, &allocations, detailed);
+
+		json
+	}
+}
+
+
+fn write_stats_json(out: &mut String, statistics: &AllocatorStatistics, allocations: &[AllocationInfo], detailed: bool) -> std::fmt::Result {
+	write!(out, "{{\"Total\": ")?;
+	write_memory_statistics_json(out, &statistics.total)?;
+
+	write!(out, ", \"MemoryHeaps\": [")?;
+
+	for (heap_position, heap) in statistics.heaps.iter().enumerate() {
+		if heap_position > 0 {
+			write!(out, ", ")?;
+		}
+
+		let budget = &heap.budget;
+
+		write!(out, "{{\"Index\": {}, \"Flags\": ", budget.heap_index)?;
+		write_json_string(out, &format!("{:?}", heap.flags))?;
+		write!(out, ", \"Size\": {}, \"Budget\": {{\"BudgetBytes\": {}, \"UsageBytes\": {}, \"AllocatedBytes\": {}}}, \"Stats\": ",
+			budget.size, budget.budget, budget.usage, budget.allocated_bytes)?;
+		write_memory_statistics_json(out, &heap.stats)?;
+
+		write!(out, ", \"MemoryTypes\": [")?;
+
+		let memory_types = statistics.memory_types.iter()
+			.filter(|memory_type| memory_type.heap_index == budget.heap_index);
+
+		for (type_position, memory_type) in memory_types.enumerate() {
+			if type_position > 0 {
+				write!(out, ", ")?;
+			}
+
+			write!(out, "{{\"Index\": {}, \"Flags\": ", memory_type.memory_type_index)?;
+			write_json_string(out, &format!("{:?}", memory_type.property_flags))?;
+			write!(out, ", \"Stats\": ")?;
+			write_memory_statistics_json(out, &memory_type.stats)?;
+
+			if detailed {
+				write!(out, ", \"Allocations\": [")?;
+
+				let type_allocations = allocations.iter()
+					.filter(|allocation| allocation.memory_type_index == memory_type.memory_type_index);
+
+				for (allocation_position, allocation) in type_allocations.enumerate() {
+					if allocation_position > 0 {
+						write!(out, ", ")?;
+					}
+
+					write_allocation_json(out, allocation)?;
+				}
+
+				write!(out, "]")?;
+			}
+
+			write!(out, "}}")?;
+		}
+
+		write!(out, "]}}")?;
+	}
+
+	write!(out, "]}}")
+}
+
+fn write_memory_statistics_json(out: &mut String, stats: &MemoryStatistics) -> std::fmt::Result {
+	write!(out, "{{\"AllocationCount\": {}, \"AllocationBytes\": {}, \"SubAllocationCount\": {}, \"FreeBytes\": {}, \"FreeRangeCount\": {}, \"LargestFreeRange\": {}, \"Fragmentation\": {}}}",
+		stats.allocation_count, stats.allocated_bytes, stats.sub_allocation_count, stats.free_bytes, stats.free_range_count, stats.largest_free_range, stats.fragmentation())
+}
+
+fn write_allocation_json(out: &mut String, allocation: &AllocationInfo) -> std::fmt::Result {
+	write!(out, "{{\"Name\": ")?;
+
+	match &allocation.name {
+		Some(name) => write_json_string(out, name)?,
+		None => write!(out, "null")?,
+	}
+
+	write!(out, ", \"Size\": {}, \"Location\": ", allocation.size)?;
+	write_json_string(out, &allocation.location.to_string())?;
+	write!(out, ", \"CreatedTimelineValue\": {}, \"LastUsedTimelineValue\": {}}}",
+		allocation.created_timeline_value, allocation.last_used_timeline_value)
+}
+
+fn write_json_string(out: &mut String, value: &str) -> std::fmt::Result {
+	out.push('"');
+
+	for c in value.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+			c => out.push(c),
+		}
+	}
+
+	out.push('"');
+	Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn json_string(value: &str) -> String {
+		let mut out = String::new();
+		write_json_string(&mut out, value).unwrap();
+		out
+	}
+
+	#[test]
+	fn escapes_quotes_and_backslashes() {
+		assert_eq!(json_string(r#"a "quoted" name"#), r#""a \"quoted\" name""#);
+		assert_eq!(json_string(r"C:\path\to"), r#""C:\\path\\to""#);
+	}
+
+	#[test]
+	fn escapes_control_characters() {
+		assert_eq!(json_string("a\nb\rc\td"), r#""a\nb\rc\td""#);
+		assert_eq!(json_string("\0\u{1}\u{1f}"), r#""\u0000\u0001\u001f""#);
+		assert_eq!(json_string("\u{7f}é"), "\"\u{7f}é\"");
+	}
+
+	#[test]
+	fn escapes_allocation_names() {
+		let allocation = AllocationInfo {
+			vk_memory: vk::DeviceMemory::null(),
+			size: 64,
+			memory_type_index: 0,
+			heap_index: 0,
+
+			name: Some("\"buffer\"\\\n".to_owned()),
+			location: Location::caller(),
+
+			created_timeline_value: 1,
+			last_used_timeline_value: 2,
+			sequence_number: 0,
+		};
+
+		let mut out = String::new();
+		write_allocation_json(&mut out, &allocation).unwrap();
+
+		assert!(out.starts_with(r#"{"Name": "\"buffer\"\\\n", "Size": 64"#), "{out}");
+	}
+
+	#[test]
+	fn no_fragmentation_without_free_bytes() {
+		let stats = MemoryStatistics {
+			allocation_count: 1,
+			allocated_bytes: 1024,
+			sub_allocation_count: 4,
+			..MemoryStatistics::default()
+		};
+
+		assert_eq!(stats.fragmentation(), 0.0);
+
+		let mut out = String::new();
+		write_memory_statistics_json(&mut out, &stats).unwrap();
+		assert!(out.ends_with("\"Fragmentation\": 0}"), "{out}");
+	}
+
+	#[test]
+	fn fragmentation_from_largest_free_range() {
+		let contiguous = MemoryStatistics { free_bytes: 100, free_range_count: 1, largest_free_range: 100, ..MemoryStatistics::default() };
+		let split = MemoryStatistics { free_bytes: 100, free_range_count: 4, largest_free_range: 25, ..MemoryStatistics::default() };
+
+		assert_eq!(contiguous.fragmentation(), 0.0);
+		assert_eq!(split.fragmentation(), 0.75);
+	}
+}
@@ -0,0 +1,159 @@
+use crate::gfx;
+use ash::vk;
+
+use anyhow::Context;
+
+use std::marker::PhantomData;
+
+
+/// A buffer of `len` elements of T, along with the memory backing it.
+/// Always has a device address, so that shaders can get at it through buffer_references.
+/// Must be destroyed with [`Buffer::queue_deletion`], otherwise it's leaked.
+#[derive(Debug)]
+#[must_use = "buffers are leaked unless queued for deletion"]
+pub struct Buffer<T> {
+	vk_buffer: vk::Buffer,
+	allocation: gfx::SubAllocation,
+
+	len: usize,
+	usage: vk::BufferUsageFlags,
+	gpu_ptr: gfx::GpuPtr<T>,
+
+	usage_tracker: gfx::UsageTracker,
+	deletion_queued: bool,
+
+	_phantom: PhantomData<T>,
+}
+
+impl<T> Buffer<T> {
+	#[track_caller]
+	pub fn new(core: &gfx::Core, allocator: &gfx::DeviceAllocator, len: usize, usage: vk::BufferUsageFlags, location: gfx::MemoryLocation) -> anyhow::Result<Buffer<T>> {
+		anyhow::ensure!(len > 0 && std::mem::size_of::<T>() > 0, "Can't create empty buffer");
+
+		let usage = usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+		let size = len.checked_mul(std::mem::size_of::<T>())
+			.context("Buffer size overflows")? as u64;
+
+		let buffer_info = vk::BufferCreateInfo::default()
+			.size(size)
+			.usage(usage)
+			.sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+		let vk_buffer = unsafe { core.vk_device.create_buffer(&buffer_info, core.allocation_callbacks())? };
+		let mut requirements = unsafe { core.vk_device.get_buffer_memory_requirements(vk_buffer) };
+
+		// Make sure every element is correctly aligned, not just the start of the buffer.
+		requirements.alignment = requirements.alignment.max(std::mem::align_of::<T>() as u64);
+
+		let allocation = match allocator.sub_allocate(core, &requirements, location, gfx::ResourceTiling::Linear) {
+			Ok(allocation) => allocation,
+			Err(error) => {
+				unsafe { core.vk_device.destroy_buffer(vk_buffer, core.allocation_callbacks()); }
+				return Err(error);
+			}
+		};
+
+		let device_address = unsafe {
+			let result = core.vk_device.bind_buffer_memory(vk_buffer, allocation.vk_memory, allocation.offset);
+			if let Err(error) = result {
+				core.vk_device.destroy_buffer(vk_buffer, core.allocation_callbacks());
+				allocator.free_sub_allocation(core, &allocation);
+				return Err(error.into());
+			}
+
+			core.vk_device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(vk_buffer))
+		};
+
+		core.register_object(vk_buffer, &format!("Buffer<{}>", std::any::type_name::<T>()));
+
+		Ok(Buffer {
+			vk_buffer,
+			allocation,
+
+			len,
+			usage,
+			gpu_ptr: gfx::GpuPtr::from_address(device_address),
+
+			usage_tracker: gfx::UsageTracker::new(),
+			deletion_queued: false,
+
+			_phantom: PhantomData,
+		})
+	}
+
+	/// Queue deletion after the last submit that was marked as using the buffer, or the latest submit if it was never marked.
+	pub fn queue_deletion(mut self, deletion_queue: &mut gfx::DeletionQueue) {
+		let timeline_value = deletion_queue.timeline_value_after_use(&self.usage_tracker);
+		deletion_queue.queue_deletion_after(self.vk_buffer, timeline_value);
+		deletion_queue.queue_deletion_after(self.allocation.clone(), timeline_value);
+		self.deletion_queued = true;
+	}
+
+	pub fn vk_buffer(&self) -> vk::Buffer {
+		self.vk_buffer
+	}
+
+	#[allow(dead_code)]
+	pub fn allocation(&self) -> &gfx::SubAllocation {
+		&self.allocation
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	#[allow(dead_code)]
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	#[allow(dead_code)]
+	pub fn size_bytes(&self) -> u64 {
+		(self.len * std::mem::size_of::<T>()) as u64
+	}
+
+	#[allow(dead_code)]
+	pub fn usage(&self) -> vk::BufferUsageFlags {
+		self.usage
+	}
+
+	/// Device address of the first element.
+	#[allow(dead_code)]
+	pub fn gpu_ptr(&self) -> gfx::GpuPtr<T> {
+		self.gpu_ptr
+	}
+
+	/// Device address of the element at `index`.
+	pub fn element_ptr(&self, index: usize) -> gfx::GpuPtr<T> {
+		assert!(index < self.len, "Buffer element {index} out of bounds (len {})", self.len);
+		self.gpu_ptr.add(index)
+	}
+
+	/// Elements of a host visible buffer.
+	/// Unsafe because nothing stops the GPU from reading or writing the buffer while the slice is alive - the caller
+	/// must make sure no submit that uses it is still in flight, e.g., by waiting on its [`gfx::UsageTracker`].
+	#[allow(dead_code)]
+	pub unsafe fn mapped_slice_mut(&mut self) -> Option<&mut [T]>
+		where T: bytemuck::Pod
+	{
+		let mapped_ptr = self.allocation.mapped_ptr?;
+
+		// Pod means any bit pattern is a valid T, and the allocation is aligned for T.
+		Some(std::slice::from_raw_parts_mut(mapped_ptr.as_ptr().cast::<T>(), self.len))
+	}
+}
+
+impl<T> Drop for Buffer<T> {
+	fn drop(&mut self) {
+		if !self.deletion_queued {
+			log::error!("Buffer<{}> dropped without being queued for deletion - {:?} and its memory will be leaked",
+				std::any::type_name::<T>(), self.vk_buffer);
+		}
+	}
+}
+
+impl<T> gfx::TrackedResource for Buffer<T> {
+	fn usage_tracker(&self) -> &gfx::UsageTracker {
+		&self.usage_tracker
+	}
+}
@@ -7,7 +7,9 @@ use winit::event_loop::OwnedDisplayHandle;
 use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 
 use std::cell::Cell;
+use std::ffi::CStr;
 use std::mem::ManuallyDrop;
+use std::rc::Rc;
 
 
 pub struct Core {
@@ -19,10 +21,35 @@ pub struct Core {
 	pub vk_physical_device: vk::PhysicalDevice,
 
 	pub vk_queue: vk::Queue,
+	pub queue_family_index: u32,
 	pub vk_cmd_pool: vk::CommandPool,
 
+	/// Dedicated transfer queue, if the device has one.
+	pub transfer_queue: Option<TransferQueue>,
+
 	pub vk_timeline_semaphore: vk::Semaphore,
-	pub timeline_value: Cell<u64>,
+	/// Value that the latest submit will signal. Shared with [`gfx::SharedDeletionQueue`].
+	pub timeline_value: Rc<Cell<u64>>,
+
+	allocation_callbacks: Option<vk::AllocationCallbacks<'static>>,
+
+	pub live_objects: gfx::LiveObjectRegistry,
+	debug_utils_fns: ash::ext::debug_utils::Device,
+
+	pub supports_memory_budget: bool,
+
+	/// Whether VK_EXT_swapchain_maintenance1 has been enabled - see [`Core::swapchain_maintenance1_fns`].
+	pub supports_swapchain_maintenance1: bool,
+
+	/// Whether VK_EXT_swapchain_colorspace is enabled, i.e., whether surfaces can report colour spaces other than sRGB.
+	#[allow(dead_code)]
+	pub supports_swapchain_colorspace: bool,
+
+	/// Whether VK_EXT_hdr_metadata has been enabled - see [`Core::hdr_metadata_fns`].
+	pub supports_hdr_metadata: bool,
+
+	/// Whether VK_KHR_present_id and VK_KHR_present_wait have both been enabled - see [`Core::present_wait_fns`].
+	pub supports_present_wait: bool,
 
 	// Must be dropped before instance.
 	// TODO(pat.m): make ManuallyDrop not required
@@ -30,6 +57,27 @@ pub struct Core {
 
 	pub surface_fns: ash::khr::surface::Instance,
 	pub swapchain_fns: ash::khr::swapchain::Device,
+
+	/// Only loaded if `supports_swapchain_maintenance1` is set, which requires VK_KHR_get_surface_capabilities2.
+	pub surface_capabilities2_fns: Option<ash::khr::get_surface_capabilities2::Instance>,
+
+	/// Only loaded if `supports_swapchain_maintenance1` is set.
+	pub swapchain_maintenance1_fns: Option<ash::ext::swapchain_maintenance1::Device>,
+
+	/// Only loaded if `supports_hdr_metadata` is set.
+	pub hdr_metadata_fns: Option<ash::ext::hdr_metadata::Device>,
+
+	/// Only loaded if `supports_present_wait` is set.
+	pub present_wait_fns: Option<ash::khr::present_wait::Device>,
+
+	/// Only loaded if VK_KHR_acceleration_structure has been enabled - nothing requests it yet.
+	pub acceleration_structure_fns: Option<ash::khr::acceleration_structure::Device>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransferQueue {
+	pub vk_queue: vk::Queue,
+	pub family_index: u32,
 }
 
 impl Core {
@@ -48,8 +96,31 @@ impl Core {
 		let mut required_extensions = ash_window::enumerate_required_extensions(raw_display_handle)?.to_owned();
 		required_extensions.push(c"VK_EXT_debug_utils".as_ptr());
 
+		let instance_extensions = unsafe { vk_entry.enumerate_instance_extension_properties(None)? };
+		let supports_instance_extension = |name: &CStr| instance_extensions.iter()
+			.any(|props| props.extension_name_as_c_str() == Ok(name));
+
+		// Required by VK_EXT_swapchain_maintenance1, which is checked for once we have a physical device.
+		let supports_surface_maintenance1 = supports_instance_extension(vk::KHR_GET_SURFACE_CAPABILITIES2_NAME)
+			&& supports_instance_extension(vk::EXT_SURFACE_MAINTENANCE1_NAME);
+
+		if supports_surface_maintenance1 {
+			required_extensions.push(vk::KHR_GET_SURFACE_CAPABILITIES2_NAME.as_ptr());
+			required_extensions.push(vk::EXT_SURFACE_MAINTENANCE1_NAME.as_ptr());
+		}
+
+		let supports_swapchain_colorspace = supports_instance_extension(vk::EXT_SWAPCHAIN_COLORSPACE_NAME);
+		if supports_swapchain_colorspace {
+			required_extensions.push(vk::EXT_SWAPCHAIN_COLORSPACE_NAME.as_ptr());
+		} else {
+			log::warn!("VK_EXT_swapchain_colorspace not supported - HDR output will not be available");
+		}
+
 		let validation_layer_name = [c"VK_LAYER_KHRONOS_validation".as_ptr()];
 
+		// Only Some if the track-host-allocations feature is enabled.
+		let allocation_callbacks = gfx::allocation_callbacks();
+
 		let vk_instance = unsafe {
 			let mut debug_create_info = gfx::new_debug_create_info();
 			let vk_instance_info = vk::InstanceCreateInfo::default()
@@ -58,13 +129,19 @@ impl Core {
 				.enabled_layer_names(&validation_layer_name)
 				.push_next(&mut debug_create_info); // Allow messages from create_instance to be caught
 
-			vk_entry.create_instance(&vk_instance_info, None)?
+			vk_entry.create_instance(&vk_instance_info, allocation_callbacks.as_ref())?
 		};
 
-		let debug = gfx::Debug::install(&vk_entry, &vk_instance)?;
+		let debug = gfx::Debug::install(&vk_entry, &vk_instance, allocation_callbacks.as_ref())?;
 
 		let vk_physical_device = select_physical_device(&vk_instance)?;
 		let queue_family_idx = select_graphics_queue_family(&vk_instance, vk_physical_device)?;
+		let transfer_queue_family_idx = select_transfer_queue_family(&vk_instance, vk_physical_device);
+
+		match transfer_queue_family_idx {
+			Some(family_idx) => log::info!("Using dedicated transfer queue family {family_idx}"),
+			None => log::info!("No dedicated transfer queue family - uploads will go through the graphics queue"),
+		}
 
 		let device_properties = unsafe { vk_instance.get_physical_device_properties(vk_physical_device) };
 		let extensions = unsafe { vk_instance.enumerate_device_extension_properties(vk_physical_device)? };
@@ -80,20 +157,84 @@ impl Core {
 		log::info!("Supported device extensions: {extensions:?}");
 		// TODO(pat.m): check for vk::KHR_SWAPCHAIN_MUTABLE_FORMAT_NAME
 
+		let supports_extension = |name: &CStr| extensions.iter().any(|ext| ext.as_bytes() == name.to_bytes());
+
+		let supports_memory_budget = supports_extension(vk::EXT_MEMORY_BUDGET_NAME);
+		if !supports_memory_budget {
+			log::warn!("VK_EXT_memory_budget not supported - memory budgets will be estimated from heap sizes");
+		}
+
+		let supports_swapchain_maintenance1 = supports_surface_maintenance1
+			&& supports_extension(vk::EXT_SWAPCHAIN_MAINTENANCE1_NAME)
+			&& unsafe {
+				let mut maintenance1_features = vk::PhysicalDeviceSwapchainMaintenance1FeaturesEXT::default();
+				let mut features = vk::PhysicalDeviceFeatures2::default().push_next(&mut maintenance1_features);
+				vk_instance.get_physical_device_features2(vk_physical_device, &mut features);
+				maintenance1_features.swapchain_maintenance1 == vk::TRUE
+			};
+
+		if !supports_swapchain_maintenance1 {
+			log::warn!("VK_EXT_swapchain_maintenance1 not supported - retired swapchains will be destroyed conservatively");
+		}
+
+		let supports_hdr_metadata = supports_extension(vk::EXT_HDR_METADATA_NAME);
+
+		// Present wait is useless without present ids, so only enable them together.
+		let supports_present_wait = supports_extension(vk::KHR_PRESENT_ID_NAME)
+			&& supports_extension(vk::KHR_PRESENT_WAIT_NAME)
+			&& unsafe {
+				let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::default();
+				let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::default();
+				let mut features = vk::PhysicalDeviceFeatures2::default()
+					.push_next(&mut present_id_features)
+					.push_next(&mut present_wait_features);
+				vk_instance.get_physical_device_features2(vk_physical_device, &mut features);
+				present_id_features.present_id == vk::TRUE && present_wait_features.present_wait == vk::TRUE
+			};
+
+		if !supports_present_wait {
+			log::warn!("VK_KHR_present_wait not supported - frame pacing will rely on the frames in flight limit only");
+		}
+
 		let vk_device = unsafe {
-			let ext_names = [
+			let mut ext_names = vec![
 				vk::KHR_SWAPCHAIN_NAME.as_ptr(),
 
 				// TODO(pat.m): can we be sure this is available.
 				vk::KHR_SWAPCHAIN_MUTABLE_FORMAT_NAME.as_ptr(),
 			];
 
-			let queue_create_infos = [
+			if supports_memory_budget {
+				ext_names.push(vk::EXT_MEMORY_BUDGET_NAME.as_ptr());
+			}
+
+			if supports_swapchain_maintenance1 {
+				ext_names.push(vk::EXT_SWAPCHAIN_MAINTENANCE1_NAME.as_ptr());
+			}
+
+			if supports_hdr_metadata {
+				ext_names.push(vk::EXT_HDR_METADATA_NAME.as_ptr());
+			}
+
+			if supports_present_wait {
+				ext_names.push(vk::KHR_PRESENT_ID_NAME.as_ptr());
+				ext_names.push(vk::KHR_PRESENT_WAIT_NAME.as_ptr());
+			}
+
+			let mut queue_create_infos = vec![
 				vk::DeviceQueueCreateInfo::default()
 					.queue_family_index(queue_family_idx)
 					.queue_priorities(&[1.0])
 			];
 
+			if let Some(family_idx) = transfer_queue_family_idx {
+				queue_create_infos.push(
+					vk::DeviceQueueCreateInfo::default()
+						.queue_family_index(family_idx)
+						.queue_priorities(&[1.0])
+				);
+			}
+
 			let mut features_12 = vk::PhysicalDeviceVulkan12Features::default()
 				.timeline_semaphore(true)
 				.buffer_device_address(true)
@@ -103,22 +244,45 @@ impl Core {
 				.dynamic_rendering(true)
 				.synchronization2(true);
 
-			let device_create_info = vk::DeviceCreateInfo::default()
+			let mut swapchain_maintenance1_features = vk::PhysicalDeviceSwapchainMaintenance1FeaturesEXT::default()
+				.swapchain_maintenance1(true);
+
+			let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::default()
+				.present_id(true);
+
+			let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::default()
+				.present_wait(true);
+
+			let mut device_create_info = vk::DeviceCreateInfo::default()
 				.queue_create_infos(&queue_create_infos)
 				.enabled_extension_names(&ext_names)
 				.push_next(&mut features_12)
 				.push_next(&mut features_13);
 
-			vk_instance.create_device(vk_physical_device, &device_create_info, None)?
+			if supports_swapchain_maintenance1 {
+				device_create_info = device_create_info.push_next(&mut swapchain_maintenance1_features);
+			}
+
+			if supports_present_wait {
+				device_create_info = device_create_info
+					.push_next(&mut present_id_features)
+					.push_next(&mut present_wait_features);
+			}
+
+			vk_instance.create_device(vk_physical_device, &device_create_info, allocation_callbacks.as_ref())?
 		};
 
 		let vk_queue = unsafe { vk_device.get_device_queue(queue_family_idx, 0) };
+		let transfer_queue = transfer_queue_family_idx.map(|family_index| TransferQueue {
+			vk_queue: unsafe { vk_device.get_device_queue(family_index, 0) },
+			family_index,
+		});
 		let vk_cmd_pool = unsafe {
 			let create_info = vk::CommandPoolCreateInfo::default()
 				.queue_family_index(queue_family_idx)
 				.flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
 
-			vk_device.create_command_pool(&create_info, None)?
+			vk_device.create_command_pool(&create_info, allocation_callbacks.as_ref())?
 		};
 
 		let vk_timeline_semaphore = unsafe {
@@ -126,11 +290,17 @@ impl Core {
 				.semaphore_type(vk::SemaphoreType::TIMELINE)
 				.initial_value(0);
 
-			vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default().push_next(&mut timeline_create_info), None)?
+			vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default().push_next(&mut timeline_create_info), allocation_callbacks.as_ref())?
 		};
 
 		let surface_fns = ash::khr::surface::Instance::new(&vk_entry, &vk_instance);
 		let swapchain_fns = ash::khr::swapchain::Device::new(&vk_instance, &vk_device);
+		let surface_capabilities2_fns = supports_swapchain_maintenance1.then(|| ash::khr::get_surface_capabilities2::Instance::new(&vk_entry, &vk_instance));
+		let swapchain_maintenance1_fns = supports_swapchain_maintenance1.then(|| ash::ext::swapchain_maintenance1::Device::new(&vk_instance, &vk_device));
+		let hdr_metadata_fns = supports_hdr_metadata.then(|| ash::ext::hdr_metadata::Device::new(&vk_instance, &vk_device));
+		let present_wait_fns = supports_present_wait.then(|| ash::khr::present_wait::Device::new(&vk_instance, &vk_device));
+		let acceleration_structure_fns = None;
+		let debug_utils_fns = ash::ext::debug_utils::Device::new(&vk_instance, &vk_device);
 
 		log::info!("gfx core init");
 
@@ -143,15 +313,34 @@ impl Core {
 			vk_physical_device,
 
 			vk_queue,
+			queue_family_index: queue_family_idx,
 			vk_cmd_pool,
 
+			transfer_queue,
+
 			vk_timeline_semaphore,
-			timeline_value: Cell::new(0),
+			timeline_value: Rc::new(Cell::new(0)),
+
+			allocation_callbacks,
+
+			live_objects: gfx::LiveObjectRegistry::default(),
+			debug_utils_fns,
+
+			supports_memory_budget,
+			supports_swapchain_maintenance1,
+			supports_swapchain_colorspace,
+			supports_hdr_metadata,
+			supports_present_wait,
 
 			debug: ManuallyDrop::new(debug),
 
 			surface_fns,
 			swapchain_fns,
+			surface_capabilities2_fns,
+			swapchain_maintenance1_fns,
+			hdr_metadata_fns,
+			present_wait_fns,
+			acceleration_structure_fns,
 		})
 	}
 
@@ -159,7 +348,7 @@ impl Core {
 		let display_handle = self.display_handle.display_handle()?.as_raw();
 		let window_handle = window_handle.window_handle()?.as_raw();
 		unsafe {
-			ash_window::create_surface(&self.vk_entry, &self.vk_instance, display_handle, window_handle, None)
+			ash_window::create_surface(&self.vk_entry, &self.vk_instance, display_handle, window_handle, self.allocation_callbacks())
 				.map_err(Into::into)
 		}
 	}
@@ -171,10 +360,31 @@ impl Core {
 		}
 	}
 
-	pub fn next_timeline_value(&self) -> u64 {
-		let next_value = self.timeline_value.get() + 1;
-		self.timeline_value.set(next_value);
-		next_value
+	/// Allocation callbacks that must be passed to every create and destroy call.
+	pub fn allocation_callbacks(&self) -> Option<&vk::AllocationCallbacks<'static>> {
+		self.allocation_callbacks.as_ref()
+	}
+
+	/// Give `handle` a debug name, and track it in [`Core::live_objects`] until it is destroyed through the deletion queue.
+	#[track_caller]
+	pub fn register_object<H: vk::Handle + Copy>(&self, handle: H, name: &str) {
+		let location = std::panic::Location::caller();
+		self.live_objects.register(handle, name, location);
+
+		// Names are only for debugging, so failing to set one isn't worth reporting.
+		if let Ok(name) = std::ffi::CString::new(name) {
+			unsafe {
+				let name_info = vk::DebugUtilsObjectNameInfoEXT::default()
+					.object_handle(handle)
+					.object_name(&name);
+
+				let _ = self.debug_utils_fns.set_debug_utils_object_name(&name_info);
+			}
+		}
+	}
+
+	pub fn unregister_object<H: vk::Handle + Copy>(&self, handle: H) {
+		self.live_objects.unregister(handle);
 	}
 
 	pub fn wait_idle(&self) {
@@ -189,14 +399,20 @@ impl Drop for Core {
 		unsafe {
 			self.vk_device.device_wait_idle().unwrap();
 
-			self.vk_device.destroy_semaphore(self.vk_timeline_semaphore, None);
+			self.live_objects.report_leaks();
+
+			let allocation_callbacks = self.allocation_callbacks.as_ref();
+
+			self.vk_device.destroy_semaphore(self.vk_timeline_semaphore, allocation_callbacks);
 
-			self.vk_device.destroy_command_pool(self.vk_cmd_pool, None);
-			self.vk_device.destroy_device(None);
+			self.vk_device.destroy_command_pool(self.vk_cmd_pool, allocation_callbacks);
+			self.vk_device.destroy_device(allocation_callbacks);
 
-			ManuallyDrop::take(&mut self.debug).destroy();
-			self.vk_instance.destroy_instance(None);
+			ManuallyDrop::take(&mut self.debug).destroy(allocation_callbacks);
+			self.vk_instance.destroy_instance(allocation_callbacks);
 		}
+
+		gfx::report_host_allocations(true);
 	}
 }
 
@@ -232,105 +448,22 @@ fn select_graphics_queue_family(vk_instance: &ash::Instance, physical_device: vk
 	}
 }
 
+/// Find a queue family that supports transfers and nothing else, which usually maps to a DMA engine that can run alongside graphics work.
+/// Families with coarse image transfer granularity are skipped so that any image region can be uploaded.
+fn select_transfer_queue_family(vk_instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Option<u32> {
+	let unit_granularity = vk::Extent3D { width: 1, height: 1, depth: 1 };
 
+	unsafe {
+		vk_instance.get_physical_device_queue_family_properties(physical_device)
+			.into_iter()
+			.enumerate()
+			.find(|(_, family_properties)| {
+				let queue_flags = family_properties.queue_flags;
 
-
-#[derive(Debug)]
-pub struct StagingBuffer {
-	vk_memory: vk::DeviceMemory,
-	vk_buffer: vk::Buffer,
-
-	mapped_ptr: *mut u8,
-	allocation_size: usize,
-	write_cursor: usize,
-
-	pub device_address: vk::DeviceAddress,
-
-	last_upload_timeline_value: u64,
-}
-
-impl StagingBuffer {
-	pub fn new(core: &gfx::Core, allocator: &gfx::DeviceAllocator) -> anyhow::Result<StagingBuffer> {
-		let allocation_size = 100 << 20;
-		let vk_memory = allocator.allocate_staging_memory(core, allocation_size)?;
-
-		let buffer_usage = vk::BufferUsageFlags::TRANSFER_SRC
-			| vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
-
-		let buffer_info = vk::BufferCreateInfo::default()
-			.size(allocation_size)
-			.usage(buffer_usage);
-
-		let vk_buffer = unsafe { core.vk_device.create_buffer(&buffer_info, None)? };
-		let buffer_requirements = unsafe { core.vk_device.get_buffer_memory_requirements(vk_buffer) };
-
-		log::info!("Staging buffer memory requirements: size {}MiB - align {}", buffer_requirements.size >> 20, buffer_requirements.alignment);
-
-		// vulkan guarantees that vk_memory will be adequately aligned for anything we want to put in it.
-		// its only at non-zero offsets that we need to care about alignment.
-		unsafe {
-			core.vk_device.bind_buffer_memory(vk_buffer, vk_memory, 0)?;
-		}
-
-		let mapped_ptr = unsafe {
-			let offset = 0;
-			let memory_map_flags = vk::MemoryMapFlags::empty();
-			core.vk_device.map_memory(vk_memory, offset, vk::WHOLE_SIZE, memory_map_flags)?.cast()
-		};
-
-		let device_address = unsafe {
-			core.vk_device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(vk_buffer))
-		};
-
-		// TODO(pat.m): must align mapped_ptr range to VkPhysicalDeviceLimits::nonCoherentAtomSize if memory isn't HOST_COHERENT.
-
-		Ok(StagingBuffer {
-			vk_memory,
-			vk_buffer,
-
-			mapped_ptr,
-			allocation_size: allocation_size as usize,
-			write_cursor: 0,
-
-			device_address,
-
-			last_upload_timeline_value: 0,
-		})
-	}
-
-	pub fn queue_deletion(&self, deletion_queue: &mut gfx::DeletionQueue) {
-		// No unmap required! vkFreeMemory will implicitly unmap memory.
-
-		deletion_queue.queue_deletion_after(self.vk_buffer, self.last_upload_timeline_value);
-		deletion_queue.queue_deletion_after(self.vk_memory, self.last_upload_timeline_value + 1);
-	}
-
-	pub fn allocate_write_space(&mut self, size: usize, alignment: usize) -> usize {
-		let align_offset = unsafe {
-			self.mapped_ptr.add(self.write_cursor).align_offset(alignment)
-		};
-
-		let offset = self.write_cursor + align_offset;
-		self.write_cursor = offset + size;
-
-		assert!(self.write_cursor <= self.allocation_size, "Staging buffer overflow");
-
-		offset
-	}
-
-	pub fn write<T>(&mut self, data: &T) -> vk::DeviceAddress
-		where T: bytemuck::NoUninit + Copy
-	{
-		let alignment = std::mem::align_of::<T>().max(8);
-		let offset = self.allocate_write_space(std::mem::size_of::<T>(), alignment);
-
-		unsafe {
-			self.mapped_ptr
-				.add(offset)
-				.cast::<T>()
-				.write(*data);
-		}
-
-		self.device_address + offset as u64
+				queue_flags.contains(vk::QueueFlags::TRANSFER)
+					&& !queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+					&& family_properties.min_image_transfer_granularity == unit_granularity
+			})
+			.map(|(idx, _)| idx as u32)
 	}
 }
@@ -7,10 +7,11 @@ pub struct Debug {
 }
 
 impl Debug {
-	pub fn install(vk_entry: &ash::Entry, vk_instance: &ash::Instance) -> anyhow::Result<Debug> {
+	pub fn install(vk_entry: &ash::Entry, vk_instance: &ash::Instance, allocation_callbacks: Option<&vk::AllocationCallbacks>) -> anyhow::Result<Debug> {
+		#[allow(clippy::needless_borrow)]
 		let debug_util_fns = ash::ext::debug_utils::Instance::new(&vk_entry, &vk_instance);
 		let vk_debug_messenger = unsafe {
-			debug_util_fns.create_debug_utils_messenger(&new_debug_create_info(), None)?
+			debug_util_fns.create_debug_utils_messenger(&new_debug_create_info(), allocation_callbacks)?
 		};
 
 		Ok(Debug {
@@ -19,9 +20,9 @@ impl Debug {
 		})
 	}
 
-	pub fn destroy(self) {
+	pub fn destroy(self, allocation_callbacks: Option<&vk::AllocationCallbacks>) {
 		unsafe {
-			self.debug_util_fns.destroy_debug_utils_messenger(self.vk_debug_messenger, None);
+			self.debug_util_fns.destroy_debug_utils_messenger(self.vk_debug_messenger, allocation_callbacks);
 		}
 	}
 }
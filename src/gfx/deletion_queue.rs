@@ -1,139 +1,444 @@
 use crate::gfx;
 use ash::vk;
+use ash::vk::Handle;
 
-#[derive(Debug)]
-pub enum DeletableResource {
-	DeviceMemory(vk::DeviceMemory),
+use std::any::TypeId;
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
 
-	Swapchain(vk::SwapchainKHR),
-	Surface(vk::SurfaceKHR),
 
-	Semaphore(vk::Semaphore),
+/// Anything that can be queued in a [`DeletionQueue`].
+/// Vulkan handles are covered by `impl_deletable_device_objects` - only resources that need something other than a plain vkDestroy* need a hand written impl.
+pub trait DeletableResource: std::fmt::Debug {
+	/// Identifies the object being deleted, so that the same object can't be queued for deletion twice.
+	/// Only needs to be unique among objects of the same type.
+	fn deletion_key(&self) -> (u64, u64);
 
-	ImageView(vk::ImageView),
-	Image(vk::Image),
-	Buffer(vk::Buffer),
-	Pipeline(vk::Pipeline),
+	/// Must only be called once the GPU is done with the resource, and only once.
+	unsafe fn destroy(&self, core: &gfx::Core, allocator: &gfx::DeviceAllocator);
 }
 
+/// Implement [`DeletableResource`] for handles destroyed through `vk_device.$destroy_fn(handle, allocation_callbacks)`.
+macro_rules! impl_deletable_device_objects {
+	($($handle:ty => $destroy_fn:ident,)*) => {
+		$(
+			impl DeletableResource for $handle {
+				fn deletion_key(&self) -> (u64, u64) {
+					(self.as_raw(), 0)
+				}
 
-impl From<vk::DeviceMemory> for DeletableResource {
-	fn from(resource: vk::DeviceMemory) -> Self {
-		Self::DeviceMemory(resource)
+				unsafe fn destroy(&self, core: &gfx::Core, _allocator: &gfx::DeviceAllocator) {
+					core.unregister_object(*self);
+					unsafe { core.vk_device.$destroy_fn(*self, core.allocation_callbacks()) }
+				}
+			}
+		)*
 	}
 }
 
-impl From<vk::SwapchainKHR> for DeletableResource {
-	fn from(resource: vk::SwapchainKHR) -> Self {
-		Self::Swapchain(resource)
-	}
+impl_deletable_device_objects! {
+	vk::Semaphore => destroy_semaphore,
+	vk::Fence => destroy_fence,
+	vk::CommandPool => destroy_command_pool,
+	vk::QueryPool => destroy_query_pool,
+
+	vk::ImageView => destroy_image_view,
+	vk::Image => destroy_image,
+	vk::Buffer => destroy_buffer,
+	vk::Sampler => destroy_sampler,
+
+	vk::Pipeline => destroy_pipeline,
+	vk::PipelineLayout => destroy_pipeline_layout,
+	vk::ShaderModule => destroy_shader_module,
+
+	vk::DescriptorSetLayout => destroy_descriptor_set_layout,
+	vk::DescriptorPool => destroy_descriptor_pool,
 }
 
-impl From<vk::SurfaceKHR> for DeletableResource {
-	fn from(resource: vk::SurfaceKHR) -> Self {
-		Self::Surface(resource)
+impl DeletableResource for vk::DeviceMemory {
+	fn deletion_key(&self) -> (u64, u64) {
+		(self.as_raw(), 0)
+	}
+
+	unsafe fn destroy(&self, core: &gfx::Core, allocator: &gfx::DeviceAllocator) {
+		unsafe { allocator.free(core, *self) }
 	}
 }
 
-impl From<vk::Semaphore> for DeletableResource {
-	fn from(resource: vk::Semaphore) -> Self {
-		Self::Semaphore(resource)
+impl DeletableResource for gfx::SubAllocation {
+	fn deletion_key(&self) -> (u64, u64) {
+		(self.vk_memory.as_raw(), self.offset)
+	}
+
+	unsafe fn destroy(&self, core: &gfx::Core, allocator: &gfx::DeviceAllocator) {
+		unsafe { allocator.free_sub_allocation(core, self) }
 	}
 }
 
-impl From<vk::Image> for DeletableResource {
-	fn from(resource: vk::Image) -> Self {
-		Self::Image(resource)
+impl DeletableResource for vk::SwapchainKHR {
+	fn deletion_key(&self) -> (u64, u64) {
+		(self.as_raw(), 0)
+	}
+
+	unsafe fn destroy(&self, core: &gfx::Core, _allocator: &gfx::DeviceAllocator) {
+		core.unregister_object(*self);
+		unsafe { core.swapchain_fns.destroy_swapchain(*self, core.allocation_callbacks()) }
 	}
 }
 
-impl From<vk::ImageView> for DeletableResource {
-	fn from(resource: vk::ImageView) -> Self {
-		Self::ImageView(resource)
+impl DeletableResource for vk::SurfaceKHR {
+	fn deletion_key(&self) -> (u64, u64) {
+		(self.as_raw(), 0)
+	}
+
+	unsafe fn destroy(&self, core: &gfx::Core, _allocator: &gfx::DeviceAllocator) {
+		core.unregister_object(*self);
+		unsafe { core.surface_fns.destroy_surface(*self, core.allocation_callbacks()) }
 	}
 }
 
-impl From<vk::Buffer> for DeletableResource {
-	fn from(resource: vk::Buffer) -> Self {
-		Self::Buffer(resource)
+impl DeletableResource for vk::AccelerationStructureKHR {
+	fn deletion_key(&self) -> (u64, u64) {
+		(self.as_raw(), 0)
+	}
+
+	unsafe fn destroy(&self, core: &gfx::Core, _allocator: &gfx::DeviceAllocator) {
+		let Some(acceleration_structure_fns) = &core.acceleration_structure_fns else {
+			log::error!("Can't destroy {self:?} without VK_KHR_acceleration_structure - leaking it");
+			return
+		};
+
+		core.unregister_object(*self);
+		unsafe { acceleration_structure_fns.destroy_acceleration_structure(*self, core.allocation_callbacks()) }
 	}
 }
 
-impl From<vk::Pipeline> for DeletableResource {
-	fn from(resource: vk::Pipeline) -> Self {
-		Self::Pipeline(resource)
+/// Command buffers are freed back to the pool they were allocated from.
+impl DeletableResource for (vk::CommandPool, vk::CommandBuffer) {
+	fn deletion_key(&self) -> (u64, u64) {
+		(self.0.as_raw(), self.1.as_raw())
+	}
+
+	unsafe fn destroy(&self, core: &gfx::Core, _allocator: &gfx::DeviceAllocator) {
+		unsafe { core.vk_device.free_command_buffers(self.0, &[self.1]) }
 	}
 }
 
 
 pub struct PendingDeletion {
+	type_name: &'static str,
+	key: (TypeId, u64, u64),
+	resource: Box<dyn DeletableResource>,
+}
+
+/// Resources that also have to wait for fences that aren't tied to the timeline, e.g., present fences.
+struct FenceWait {
 	timeline_value: u64,
-	resource: DeletableResource,
+	vk_fences: Vec<vk::Fence>,
+
+	// Also waits for every fence wait queued before this one.
+	after_earlier_waits: bool,
+
+	deletions: Vec<PendingDeletion>,
 }
 
-#[derive(Default)]
+/// Resources waiting to be destroyed, bucketed by the timeline value they are waiting on.
+/// Within a bucket, resources are destroyed in the order they were queued, so dependents must be queued before the things they depend on.
 pub struct DeletionQueue {
-	pending_deletions: Vec<PendingDeletion>,
+	buckets: BTreeMap<u64, Vec<PendingDeletion>>,
+	fence_waits: Vec<FenceWait>,
+	pending_keys: HashSet<(TypeId, u64, u64)>,
+
+	/// Shared with [`gfx::Core`], so that deletions can be timed by the latest submit without access to it.
+	latest_timeline_value: Rc<Cell<u64>>,
 }
 
 impl DeletionQueue {
-	pub fn queue_deletion_after(&mut self, resource: impl Into<DeletableResource>, timeline_value: u64) {
-		self.pending_deletions.push(PendingDeletion {
-			resource: resource.into(),
+	pub fn new(core: &gfx::Core) -> DeletionQueue {
+		DeletionQueue {
+			buckets: BTreeMap::new(),
+			fence_waits: Vec::new(),
+			pending_keys: HashSet::new(),
+
+			latest_timeline_value: core.timeline_value.clone(),
+		}
+	}
+
+	/// Queue `resource` to be destroyed once the GPU has reached `timeline_value`.
+	/// Queueing something that is already pending deletion is an error, and is ignored.
+	pub fn queue_deletion_after<R>(&mut self, resource: R, timeline_value: u64)
+		where R: DeletableResource + 'static
+	{
+		if let Some(deletion) = self.new_pending_deletion(resource) {
+			self.buckets.entry(timeline_value).or_default().push(deletion);
+		}
+	}
+
+	/// Queue `resource` to be destroyed once the GPU has reached `timeline_value` _and_ every fence in `vk_fences` is signalled.
+	/// Deletions queued with the same timeline value and fences back to back are destroyed in the order they were queued.
+	pub fn queue_deletion_after_fences<R>(&mut self, resource: R, timeline_value: u64, vk_fences: &[vk::Fence])
+		where R: DeletableResource + 'static
+	{
+		let Some(deletion) = self.new_pending_deletion(resource) else {
+			return
+		};
+
+		match self.fence_waits.last_mut() {
+			Some(wait) if wait.timeline_value == timeline_value && wait.vk_fences == vk_fences && !wait.after_earlier_waits => {
+				wait.deletions.push(deletion);
+			}
+
+			_ => self.fence_waits.push(FenceWait {
+				timeline_value,
+				vk_fences: vk_fences.to_vec(),
+				after_earlier_waits: false,
+				deletions: vec![deletion],
+			}),
+		}
+	}
+
+	/// Queue `resource` to be destroyed once the GPU has reached `timeline_value` _and_ everything queued with fences before it has been destroyed.
+	/// For things that must outlive whatever was created from them, e.g., a surface and every swapchain retired from it.
+	pub fn queue_deletion_after_fenced_deletions<R>(&mut self, resource: R, timeline_value: u64)
+		where R: DeletableResource + 'static
+	{
+		let Some(deletion) = self.new_pending_deletion(resource) else {
+			return
+		};
+
+		self.fence_waits.push(FenceWait {
 			timeline_value,
+			vk_fences: Vec::new(),
+			after_earlier_waits: true,
+			deletions: vec![deletion],
 		});
 	}
 
-	pub fn queue_deletion(&mut self, resource: impl Into<DeletableResource>, core: &gfx::Core) {
+	fn new_pending_deletion<R>(&mut self, resource: R) -> Option<PendingDeletion>
+		where R: DeletableResource + 'static
+	{
+		let key = pending_key(&resource);
+
+		if !self.pending_keys.insert(key) {
+			log::error!("Double deletion of {resource:?} - ignoring");
+			return None;
+		}
+
+		Some(PendingDeletion {
+			type_name: std::any::type_name::<R>(),
+			key,
+			resource: Box::new(resource),
+		})
+	}
+
+	pub fn queue_deletion(&mut self, resource: impl DeletableResource + 'static, core: &gfx::Core) {
 		self.queue_deletion_after(resource, core.timeline_value.get());
 	}
 
-	pub fn destroy_ready(&mut self, core: &gfx::Core) {
+	/// Queue `resource` to be destroyed once the last submit that used it, according to `usage_tracker`, completes.
+	pub fn queue_deletion_after_use(&mut self, resource: impl DeletableResource + 'static, usage_tracker: &gfx::UsageTracker) {
+		let timeline_value = self.timeline_value_after_use(usage_tracker);
+		self.queue_deletion_after(resource, timeline_value);
+	}
+
+	/// Timeline value a resource tracked by `usage_tracker` can be destroyed after.
+	/// Resources that were never marked as used may still have been used by a submit that didn't mark them, so have to wait for the latest submit.
+	pub fn timeline_value_after_use(&self, usage_tracker: &gfx::UsageTracker) -> u64 {
+		match usage_tracker.last_used_timeline_value() {
+			0 => self.latest_timeline_value.get(),
+			last_used => last_used,
+		}
+	}
+
+	pub fn destroy_ready(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator) {
 		let current_timeline_value = unsafe {
 			core.vk_device.get_semaphore_counter_value(core.vk_timeline_semaphore).unwrap()
 		};
 
-		self.pending_deletions.sort_by_key(|d| d.timeline_value);
-		let partition_point = self.pending_deletions.partition_point(|d| d.timeline_value <= current_timeline_value);
+		while let Some(bucket) = self.buckets.first_entry() {
+			if *bucket.key() > current_timeline_value {
+				break;
+			}
+
+			for PendingDeletion{resource, key, ..} in bucket.remove() {
+				self.pending_keys.remove(&key);
+
+				unsafe {
+					destroy_resource_immediate(core, allocator, resource);
+				}
+			}
+		}
+
+		// Everything is checked before anything is destroyed, so no fence is queried after it's gone.
+		let mut earlier_waits_ready = true;
+		let (ready, waiting) = std::mem::take(&mut self.fence_waits).into_iter()
+			.partition(|wait| {
+				let is_ready = (earlier_waits_ready || !wait.after_earlier_waits)
+					&& wait.timeline_value <= current_timeline_value
+					&& wait.vk_fences.iter().all(|&vk_fence| unsafe { core.vk_device.get_fence_status(vk_fence) == Ok(true) });
+
+				earlier_waits_ready &= is_ready;
+				is_ready
+			});
+
+		self.fence_waits = waiting;
+
+		for PendingDeletion{resource, key, ..} in ready.into_iter().flat_map(|wait: FenceWait| wait.deletions) {
+			self.pending_keys.remove(&key);
 
-		for PendingDeletion{resource, ..} in self.pending_deletions.drain(..partition_point) {
 			unsafe {
-				destroy_resource_immediate(core, resource);
+				destroy_resource_immediate(core, allocator, resource);
 			}
 		}
 	}
 
-	pub unsafe fn destroy_all_immediate(&mut self, core: &gfx::Core) {
+	pub unsafe fn destroy_all_immediate(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator) {
 		// Deletions should be submitted in order to avoid resources being destroyed after resources derived from them.
-		self.pending_deletions.sort_by_key(|d| d.timeline_value);
+		for PendingDeletion{resource, ..} in std::mem::take(&mut self.buckets).into_values().flatten() {
+			destroy_resource_immediate(core, allocator, resource);
+		}
+
+		// Waiting for the device to idle doesn't cover anything fenced, like presentation, so give it a chance to finish.
+		let mut earlier_waits_failed = false;
+
+		for wait in std::mem::take(&mut self.fence_waits) {
+			if wait.after_earlier_waits && earlier_waits_failed {
+				log::error!("Leaking {} resources that depend on leaked fenced resources", wait.deletions.len());
+				continue
+			}
 
-		for PendingDeletion{resource, ..} in self.pending_deletions.drain(..) {
-			destroy_resource_immediate(core, resource);
+			let timeout_ns = 1000*1000*1000;
+			// Anything still in use can't safely be destroyed, so leak it instead.
+			if let Err(error) = core.vk_device.wait_for_fences(&wait.vk_fences, true, timeout_ns) {
+				log::error!("Failed waiting for fences before deletion: {error} - leaking {} resources", wait.deletions.len());
+				earlier_waits_failed = true;
+				continue
+			}
+
+			for PendingDeletion{resource, ..} in wait.deletions {
+				destroy_resource_immediate(core, allocator, resource);
+			}
 		}
+
+		self.pending_keys.clear();
+	}
+
+	/// Whether `resource` is queued and hasn't been destroyed yet.
+	pub fn is_pending<R>(&self, resource: &R) -> bool
+		where R: DeletableResource + 'static
+	{
+		self.pending_keys.contains(&pending_key(resource))
 	}
-}
 
+	pub fn pending_count(&self) -> usize {
+		self.pending_keys.len()
+	}
 
-unsafe fn destroy_resource_immediate(core: &gfx::Core, resource: impl Into<DeletableResource>) {
-	use DeletableResource::*;
+	/// Number of pending deletions of each type, ordered by type name.
+	pub fn pending_counts_by_type(&self) -> Vec<(&'static str, usize)> {
+		let mut counts: Vec<(&'static str, usize)> = Vec::new();
 
-	let resource = resource.into();
-	log::debug!("Destroying resource {resource:?}");
+		let fenced_deletions = self.fence_waits.iter().flat_map(|wait| &wait.deletions);
 
-	unsafe {
-		match resource {
-			DeviceMemory(vk_resource) => core.vk_device.free_memory(vk_resource, None),
+		for deletion in self.buckets.values().flatten().chain(fenced_deletions) {
+			match counts.iter_mut().find(|(type_name, _)| *type_name == deletion.type_name) {
+				Some((_, count)) => *count += 1,
+				None => counts.push((deletion.type_name, 1)),
+			}
+		}
+
+		counts.sort();
+		counts
+	}
 
-			Swapchain(vk_resource) => core.swapchain_fns.destroy_swapchain(vk_resource, None),
-			Surface(vk_resource) => core.surface_fns.destroy_surface(vk_resource, None),
+	/// Timeline value the oldest pending deletion is waiting on.
+	/// If this stays below the current timeline value, nothing is calling `destroy_ready`.
+	pub fn oldest_pending_timeline_value(&self) -> Option<u64> {
+		let oldest_bucket = self.buckets.keys().next().copied();
+		let oldest_fence_wait = self.fence_waits.iter().map(|wait| wait.timeline_value).min();
 
-			Semaphore(vk_resource) => core.vk_device.destroy_semaphore(vk_resource, None),
+		oldest_bucket.into_iter().chain(oldest_fence_wait).min()
+	}
+
+	/// Number of pending deletions that are also waiting on fences.
+	pub fn pending_fenced_count(&self) -> usize {
+		self.fence_waits.iter().map(|wait| wait.deletions.len()).sum()
+	}
 
-			ImageView(vk_resource) => core.vk_device.destroy_image_view(vk_resource, None),
-			Image(vk_resource) => core.vk_device.destroy_image(vk_resource, None),
-			Buffer(vk_resource) => core.vk_device.destroy_buffer(vk_resource, None),
+	pub fn report_pending(&self) {
+		let Some(oldest_timeline_value) = self.oldest_pending_timeline_value() else {
+			log::info!("No pending deletions");
+			return
+		};
 
-			Pipeline(vk_resource) => core.vk_device.destroy_pipeline(vk_resource, None),
+		log::info!("{} pending deletions ({} waiting on fences), oldest waiting on timeline value {oldest_timeline_value}:",
+			self.pending_count(), self.pending_fenced_count());
+
+		for (type_name, count) in self.pending_counts_by_type() {
+			log::info!("--- {type_name}: {count}");
 		}
 	}
-}
\ No newline at end of file
+}
+
+impl Drop for DeletionQueue {
+	fn drop(&mut self) {
+		if !self.pending_keys.is_empty() {
+			log::error!("DeletionQueue dropped with {} pending deletions - these resources will be leaked", self.pending_count());
+			self.report_pending();
+		}
+	}
+}
+
+
+/// A [`DeletionQueue`] that can be shared with [`gfx::Owned`] handles, so that they can queue their own deletion when dropped.
+#[derive(Clone)]
+pub struct SharedDeletionQueue {
+	queue: Rc<RefCell<DeletionQueue>>,
+}
+
+impl SharedDeletionQueue {
+	pub fn new(core: &gfx::Core) -> SharedDeletionQueue {
+		SharedDeletionQueue {
+			queue: Rc::new(RefCell::new(DeletionQueue::new(core))),
+		}
+	}
+
+	/// Panics if the queue is already borrowed - in particular, [`gfx::Owned`] handles can't be dropped while this is held.
+	pub fn borrow_mut(&self) -> RefMut<'_, DeletionQueue> {
+		self.queue.borrow_mut()
+	}
+
+	#[allow(dead_code)]
+	pub fn queue_deletion_after(&self, resource: impl DeletableResource + 'static, timeline_value: u64) {
+		self.queue.borrow_mut().queue_deletion_after(resource, timeline_value);
+	}
+
+	/// Queue `resource` to be destroyed once the latest submit completes.
+	#[allow(dead_code)]
+	pub fn queue_deletion(&self, resource: impl DeletableResource + 'static) {
+		let mut queue = self.queue.borrow_mut();
+		let timeline_value = queue.latest_timeline_value.get();
+		queue.queue_deletion_after(resource, timeline_value);
+	}
+
+	pub fn queue_deletion_after_use(&self, resource: impl DeletableResource + 'static, usage_tracker: &gfx::UsageTracker) {
+		self.queue.borrow_mut().queue_deletion_after_use(resource, usage_tracker);
+	}
+}
+
+
+fn pending_key<R>(resource: &R) -> (TypeId, u64, u64)
+	where R: DeletableResource + 'static
+{
+	let (raw_handle, sub_key) = resource.deletion_key();
+	(TypeId::of::<R>(), raw_handle, sub_key)
+}
+
+unsafe fn destroy_resource_immediate(core: &gfx::Core, allocator: &gfx::DeviceAllocator, resource: Box<dyn DeletableResource>) {
+	log::debug!("Destroying resource {resource:?}");
+
+	unsafe {
+		resource.destroy(core, allocator);
+	}
+}
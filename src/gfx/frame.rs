@@ -0,0 +1,353 @@
+use ash::vk;
+use anyhow::Context;
+use crate::gfx;
+
+use std::rc::Rc;
+
+
+/// Commands for one frame, submitted all at once.
+/// Images acquired from any number of [`gfx::PresentableSurface`]s are presented together when the frame is submitted.
+pub struct Frame {
+	vk_cmd_buffer: vk::CommandBuffer,
+	slot_index: usize,
+
+	/// Extra semaphores the frame submission must wait on, e.g., uploads on another queue.
+	wait_semaphores: Vec<vk::SemaphoreSubmitInfo<'static>>,
+
+	/// Marked as used by this frame's submit once it happens.
+	used_resources: Vec<gfx::UsageTracker>,
+
+	presents: Vec<FramePresent>,
+}
+
+impl Frame {
+	pub fn cmd_buffer(&self) -> vk::CommandBuffer {
+		self.vk_cmd_buffer
+	}
+
+	/// Which of the [`FrameContext::frames_in_flight`] slots this frame is using.
+	/// Anything indexed by slot and used only by that slot's frames is safe to reuse once `start_frame` returns the slot again.
+	pub fn slot_index(&self) -> usize {
+		self.slot_index
+	}
+
+	/// Record that `resource` is referenced by this frame, so that it isn't deleted until the frame completes.
+	pub fn mark_used(&mut self, resource: &impl gfx::TrackedResource) {
+		self.used_resources.push(resource.usage_tracker().clone());
+	}
+
+	/// Make the frame submission wait for `vk_semaphore` to reach `value` before any commands in `stage_mask` execute.
+	/// `value` is ignored for binary semaphores.
+	pub fn wait_for_semaphore(&mut self, vk_semaphore: vk::Semaphore, value: u64, stage_mask: vk::PipelineStageFlags2) {
+		self.wait_semaphores.push(
+			vk::SemaphoreSubmitInfo::default()
+				.semaphore(vk_semaphore)
+				.value(value)
+				.stage_mask(stage_mask)
+		);
+	}
+
+	pub(super) fn add_present(&mut self, present: FramePresent) {
+		self.presents.push(present);
+	}
+}
+
+
+/// An acquired swapchain image to present once the frame is submitted.
+pub(super) struct FramePresent {
+	pub vk_swapchain: vk::SwapchainKHR,
+	pub vk_image: vk::Image,
+	pub image_index: u32,
+	pub present_semaphore: vk::Semaphore,
+	pub present_fence: Option<vk::Fence>,
+
+	/// Only set with present_wait.
+	pub present_id: Option<u64>,
+
+	pub status: Rc<gfx::PresentStatus>,
+}
+
+impl FramePresent {
+	/// Give the image back without presenting it, so that the surface can acquire again.
+	/// Without swapchain_maintenance1 the only way to do that is to recreate the swapchain.
+	fn abandon(&self, core: &gfx::Core) {
+		if let Some(swapchain_maintenance1_fns) = &core.swapchain_maintenance1_fns {
+			let result = unsafe {
+				swapchain_maintenance1_fns.release_swapchain_images(
+					&vk::ReleaseSwapchainImagesInfoEXT::default()
+						.swapchain(self.vk_swapchain)
+						.image_indices(&[self.image_index])
+				)
+			};
+
+			match result {
+				Ok(()) => {
+					self.status.mark_released();
+					return
+				}
+
+				Err(error) => log::error!("Failed to release unpresented image {} of swapchain {:?}: {error}", self.image_index, self.vk_swapchain),
+			}
+		}
+
+		self.status.request_recreate();
+	}
+}
+
+
+/// Resources used by a single frame in flight, reused once the last submit using them completes.
+struct FrameSlot {
+	vk_cmd_buffer: vk::CommandBuffer,
+	prev_submit_timeline_value: u64,
+}
+
+/// Cycles through a fixed number of frames in flight, independent of how many surfaces are being presented to or how many images they have.
+pub struct FrameContext {
+	frame_slots: Vec<FrameSlot>,
+	next_slot_index: usize,
+}
+
+impl FrameContext {
+	/// `frames_in_flight` is how many frames the CPU can record ahead of the GPU.
+	pub fn new(core: &gfx::Core, frames_in_flight: usize) -> anyhow::Result<FrameContext> {
+		anyhow::ensure!(frames_in_flight > 0, "Need at least one frame in flight");
+
+		let vk_cmd_buffers = unsafe {
+			let create_info = vk::CommandBufferAllocateInfo::default()
+				.command_buffer_count(frames_in_flight as u32)
+				.command_pool(core.vk_cmd_pool)
+				.level(vk::CommandBufferLevel::PRIMARY);
+
+			core.vk_device.allocate_command_buffers(&create_info)?
+		};
+
+		let frame_slots = vk_cmd_buffers.into_iter()
+			.map(|vk_cmd_buffer| FrameSlot {
+				vk_cmd_buffer,
+				prev_submit_timeline_value: 0,
+			})
+			.collect();
+
+		Ok(FrameContext {
+			frame_slots,
+			next_slot_index: 0,
+		})
+	}
+
+	pub fn queue_deletion(self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue) {
+		for frame_slot in self.frame_slots {
+			deletion_queue.queue_deletion_after((core.vk_cmd_pool, frame_slot.vk_cmd_buffer), frame_slot.prev_submit_timeline_value);
+		}
+	}
+
+	#[allow(dead_code)]
+	pub fn frames_in_flight(&self) -> usize {
+		self.frame_slots.len()
+	}
+
+	/// Wait for the next slot to become free, and start recording its command buffer.
+	pub fn start_frame(&mut self, core: &gfx::Core) -> anyhow::Result<Frame> {
+		let timeout_ns = 1000*1000*1000;
+
+		let slot_index = self.next_slot_index;
+		self.next_slot_index = (self.next_slot_index + 1) % self.frame_slots.len();
+
+		let frame_slot = &self.frame_slots[slot_index];
+
+		unsafe {
+			core.vk_device.wait_semaphores(
+				&vk::SemaphoreWaitInfo::default()
+					.semaphores(&[core.vk_timeline_semaphore])
+					.values(&[frame_slot.prev_submit_timeline_value]),
+				timeout_ns
+			)?;
+
+			core.vk_device.begin_command_buffer(frame_slot.vk_cmd_buffer,
+				&vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT))?;
+		}
+
+		Ok(Frame {
+			vk_cmd_buffer: frame_slot.vk_cmd_buffer,
+			slot_index,
+
+			wait_semaphores: Vec::new(),
+			used_resources: Vec::new(),
+
+			presents: Vec::new(),
+		})
+	}
+
+	/// Submit the frame, then present every image acquired for it with a single present, and return the timeline value the submit will signal.
+	/// Surfaces that report being suboptimal or out of date are recreated the next time an image is acquired from them, and lost surfaces are flagged - see [`gfx::SurfaceState::Lost`].
+	/// Failing to present is only logged, since the frame's commands were still submitted.
+	pub fn submit_frame(&mut self, core: &gfx::Core, frame: Frame) -> anyhow::Result<u64> {
+		let frame_slot = &mut self.frame_slots[frame.slot_index];
+
+		let present_barriers: Vec<_> = frame.presents.iter()
+			.map(|present| {
+				vk::ImageMemoryBarrier2::default()
+					.image(present.vk_image)
+					.old_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
+					.new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+
+					.src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+					.src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+
+					// Don't wait for anything, vkQueuePresentKHR performs visibility operations automatically
+					.dst_stage_mask(vk::PipelineStageFlags2::NONE)
+					.dst_access_mask(vk::AccessFlags2::NONE)
+					.subresource_range(
+						vk::ImageSubresourceRange::default()
+							.aspect_mask(vk::ImageAspectFlags::COLOR)
+							.base_mip_level(0)
+							.base_array_layer(0)
+							.level_count(1)
+							.layer_count(1)
+					)
+			})
+			.collect();
+
+		let timeline_value = core.timeline_value.get() + 1;
+
+		// raster output happens-before each 'present sema' signal operation, which happens-before later present.
+		let mut signal_semaphores: Vec<_> = frame.presents.iter()
+			.map(|present| {
+				vk::SemaphoreSubmitInfo::default()
+					.semaphore(present.present_semaphore)
+					.stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+			})
+			.collect();
+
+		// timeline semaphore signal op happens-after all commands complete, which happens-before the next frame where images and cmd buffers can be reused.
+		signal_semaphores.push(
+			vk::SemaphoreSubmitInfo::default()
+				.semaphore(core.vk_timeline_semaphore)
+				.stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+				.value(timeline_value)
+		);
+
+		let submit_result = unsafe {
+			if !present_barriers.is_empty() {
+				core.vk_device.cmd_pipeline_barrier2(
+					frame.vk_cmd_buffer,
+					&vk::DependencyInfo::default()
+						.image_memory_barriers(&present_barriers)
+				);
+			}
+
+			core.vk_device.end_command_buffer(frame.vk_cmd_buffer).and_then(|_| {
+				core.vk_device.queue_submit2(
+					core.vk_queue,
+					&[
+						vk::SubmitInfo2::default()
+							.wait_semaphore_infos(&frame.wait_semaphores)
+							.command_buffer_infos(&[
+								vk::CommandBufferSubmitInfo::default()
+									.command_buffer(frame.vk_cmd_buffer)
+							])
+							.signal_semaphore_infos(&signal_semaphores)
+					],
+					vk::Fence::null()
+				)
+			})
+		};
+
+		if let Err(error) = submit_result {
+			for present in frame.presents.iter() {
+				present.abandon(core);
+			}
+
+			return Err(error).context("Submitting command buffer");
+		}
+
+		// Only bump the timeline once the submit has succeeded, otherwise later waits would never complete.
+		core.timeline_value.set(timeline_value);
+		frame_slot.prev_submit_timeline_value = timeline_value;
+
+		for usage_tracker in frame.used_resources.iter() {
+			usage_tracker.mark_used(timeline_value);
+		}
+
+		if !frame.presents.is_empty() {
+			if let Err(error) = present(core, &frame.presents) {
+				log::error!("Present failed: {error}");
+			}
+		}
+
+		Ok(timeline_value)
+	}
+}
+
+
+fn present(core: &gfx::Core, presents: &[FramePresent]) -> anyhow::Result<()> {
+	let swapchains: Vec<_> = presents.iter().map(|present| present.vk_swapchain).collect();
+	let image_indices: Vec<_> = presents.iter().map(|present| present.image_index).collect();
+	let wait_semaphores: Vec<_> = presents.iter().map(|present| present.present_semaphore).collect();
+	let mut results = vec![vk::Result::SUCCESS; presents.len()];
+
+	// Present fences are all or nothing, since they depend on swapchain_maintenance1.
+	let present_fences: Vec<_> = presents.iter().filter_map(|present| present.present_fence).collect();
+
+	if !present_fences.is_empty() {
+		// The previous present of each image must have finished for it to have been acquired again, so this shouldn't block.
+		// Bounded anyway, so a misbehaving presentation engine shows up as an error rather than a hang.
+		let timeout_ns = 1000*1000*1000;
+
+		let result = unsafe {
+			core.vk_device.wait_for_fences(&present_fences, true, timeout_ns)
+				.and_then(|_| core.vk_device.reset_fences(&present_fences))
+		};
+
+		if let Err(error) = result {
+			for present in presents {
+				present.abandon(core);
+			}
+
+			return Err(error).context("Waiting for previous presents");
+		}
+	}
+
+	let mut present_fence_info = vk::SwapchainPresentFenceInfoEXT::default()
+		.fences(&present_fences);
+
+	// Likewise for present ids.
+	let present_ids: Vec<_> = presents.iter().filter_map(|present| present.present_id).collect();
+
+	let mut present_id_info = vk::PresentIdKHR::default()
+		.present_ids(&present_ids);
+
+	let mut present_info = vk::PresentInfoKHR::default()
+		.swapchains(&swapchains)
+		.image_indices(&image_indices)
+		.wait_semaphores(&wait_semaphores)
+		.results(&mut results);
+
+	if present_fences.len() == presents.len() {
+		present_info = present_info.push_next(&mut present_fence_info);
+	}
+
+	if present_ids.len() == presents.len() {
+		present_info = present_info.push_next(&mut present_id_info);
+	}
+
+	let result = unsafe {
+		core.swapchain_fns.queue_present(core.vk_queue, &present_info)
+	};
+
+	for (present, result) in presents.iter().zip(results) {
+		present.status.mark_presented();
+
+		match result {
+			vk::Result::SUCCESS => {}
+			vk::Result::SUBOPTIMAL_KHR | vk::Result::ERROR_OUT_OF_DATE_KHR => present.status.request_recreate(),
+			vk::Result::ERROR_SURFACE_LOST_KHR => present.status.mark_lost(),
+			error => log::error!("Presenting to swapchain {:?} failed: {error}", present.vk_swapchain),
+		}
+	}
+
+	match result {
+		// Handled per surface above.
+		Ok(_) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::ERROR_SURFACE_LOST_KHR) => Ok(()),
+		Err(error) => Err(error).context("Presenting to swapchains"),
+	}
+}
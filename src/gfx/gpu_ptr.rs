@@ -0,0 +1,75 @@
+use ash::vk;
+
+use std::marker::PhantomData;
+
+
+/// A device address that knows what it points to.
+/// Has the same layout as a `vk::DeviceAddress`, so can be pushed as a push constant or written into buffers
+/// wherever a shader expects a buffer_reference.
+#[repr(transparent)]
+pub struct GpuPtr<T> {
+	address: vk::DeviceAddress,
+	_phantom: PhantomData<*const T>,
+}
+
+impl<T> GpuPtr<T> {
+	pub const fn from_address(address: vk::DeviceAddress) -> GpuPtr<T> {
+		GpuPtr {
+			address,
+			_phantom: PhantomData,
+		}
+	}
+
+	pub const fn null() -> GpuPtr<T> {
+		GpuPtr::from_address(0)
+	}
+
+	pub fn is_null(&self) -> bool {
+		self.address == 0
+	}
+
+	pub fn address(&self) -> vk::DeviceAddress {
+		self.address
+	}
+
+	/// Pointer to the element `count` elements after this one, assuming this points into an array of T.
+	pub fn add(self, count: usize) -> GpuPtr<T> {
+		GpuPtr::from_address(self.address + (count * std::mem::size_of::<T>()) as u64)
+	}
+
+	pub fn cast<U>(self) -> GpuPtr<U> {
+		GpuPtr::from_address(self.address)
+	}
+}
+
+// Derives would require T: Clone etc, which isn't necessary since we never actually hold a T.
+impl<T> Clone for GpuPtr<T> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<T> Copy for GpuPtr<T> {}
+
+impl<T> PartialEq for GpuPtr<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.address == other.address
+	}
+}
+
+impl<T> Eq for GpuPtr<T> {}
+
+impl<T> std::fmt::Debug for GpuPtr<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "GpuPtr<{}>(0x{:x})", std::any::type_name::<T>(), self.address)
+	}
+}
+
+impl<T> From<GpuPtr<T>> for vk::DeviceAddress {
+	fn from(ptr: GpuPtr<T>) -> vk::DeviceAddress {
+		ptr.address
+	}
+}
+
+unsafe impl<T: 'static> bytemuck::Zeroable for GpuPtr<T> {}
+unsafe impl<T: 'static> bytemuck::Pod for GpuPtr<T> {}
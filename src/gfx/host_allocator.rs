@@ -0,0 +1,235 @@
+//! Optional tracking of host memory allocated by the driver through `VkAllocationCallbacks`.
+//! Only compiled in with the `track-host-allocations` feature, since routing every driver allocation through here isn't free.
+
+use ash::vk;
+
+
+/// Host memory usage for a single `VkSystemAllocationScope`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostAllocationScopeStats {
+	pub allocated_bytes: usize,
+	pub allocation_count: usize,
+	pub peak_allocated_bytes: usize,
+	pub total_allocation_count: usize,
+
+	/// Memory the driver allocated itself, and only told us about.
+	pub internal_allocated_bytes: usize,
+}
+
+pub const TRACKED_ALLOCATION_SCOPES: [vk::SystemAllocationScope; 5] = [
+	vk::SystemAllocationScope::COMMAND,
+	vk::SystemAllocationScope::OBJECT,
+	vk::SystemAllocationScope::CACHE,
+	vk::SystemAllocationScope::DEVICE,
+	vk::SystemAllocationScope::INSTANCE,
+];
+
+
+/// Callbacks to pass to every Vulkan create and destroy call, or None if host allocation tracking is disabled.
+#[cfg(not(feature = "track-host-allocations"))]
+pub fn allocation_callbacks() -> Option<vk::AllocationCallbacks<'static>> {
+	None
+}
+
+/// Current host allocation stats for each scope in [`TRACKED_ALLOCATION_SCOPES`], or None if host allocation tracking is disabled.
+#[cfg(not(feature = "track-host-allocations"))]
+pub fn host_allocation_stats() -> Option<[HostAllocationScopeStats; 5]> {
+	None
+}
+
+
+#[cfg(feature = "track-host-allocations")]
+pub use tracking::*;
+
+#[cfg(feature = "track-host-allocations")]
+mod tracking {
+	use super::*;
+
+	use std::alloc::Layout;
+	use std::ffi::c_void;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	struct ScopeCounters {
+		allocated_bytes: AtomicUsize,
+		allocation_count: AtomicUsize,
+		peak_allocated_bytes: AtomicUsize,
+		total_allocation_count: AtomicUsize,
+		internal_allocated_bytes: AtomicUsize,
+	}
+
+	// Drivers are free to allocate from any thread, so this needs to be global and atomic.
+	static SCOPES: [ScopeCounters; 5] = [const { ScopeCounters::new() }; 5];
+
+	impl ScopeCounters {
+		const fn new() -> Self {
+			ScopeCounters {
+				allocated_bytes: AtomicUsize::new(0),
+				allocation_count: AtomicUsize::new(0),
+				peak_allocated_bytes: AtomicUsize::new(0),
+				total_allocation_count: AtomicUsize::new(0),
+				internal_allocated_bytes: AtomicUsize::new(0),
+			}
+		}
+
+		fn stats(&self) -> HostAllocationScopeStats {
+			HostAllocationScopeStats {
+				allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+				allocation_count: self.allocation_count.load(Ordering::Relaxed),
+				peak_allocated_bytes: self.peak_allocated_bytes.load(Ordering::Relaxed),
+				total_allocation_count: self.total_allocation_count.load(Ordering::Relaxed),
+				internal_allocated_bytes: self.internal_allocated_bytes.load(Ordering::Relaxed),
+			}
+		}
+	}
+
+	fn scope_counters(scope: vk::SystemAllocationScope) -> &'static ScopeCounters {
+		let index = TRACKED_ALLOCATION_SCOPES.iter()
+			.position(|&tracked_scope| tracked_scope == scope)
+			.unwrap_or(0);
+
+		&SCOPES[index]
+	}
+
+
+	pub fn allocation_callbacks() -> Option<vk::AllocationCallbacks<'static>> {
+		Some(vk::AllocationCallbacks::default()
+			.pfn_allocation(Some(allocation_fn))
+			.pfn_reallocation(Some(reallocation_fn))
+			.pfn_free(Some(free_fn))
+			.pfn_internal_allocation(Some(internal_allocation_fn))
+			.pfn_internal_free(Some(internal_free_fn)))
+	}
+
+	pub fn host_allocation_stats() -> Option<[HostAllocationScopeStats; 5]> {
+		Some(std::array::from_fn(|index| SCOPES[index].stats()))
+	}
+
+
+	// Every allocation is prefixed by a header so that we know how much to untrack and how to deallocate on free.
+	#[derive(Clone, Copy)]
+	struct AllocationHeader {
+		layout: Layout,
+		header_size: usize,
+		scope: vk::SystemAllocationScope,
+	}
+
+	unsafe fn allocate(size: usize, alignment: usize, scope: vk::SystemAllocationScope) -> *mut c_void {
+		let alignment = alignment.max(std::mem::align_of::<AllocationHeader>());
+
+		// Panicking here would unwind across the FFI boundary, so anything unrepresentable is just a failed allocation.
+		let Some(header_size) = std::mem::size_of::<AllocationHeader>().checked_next_multiple_of(alignment) else {
+			return std::ptr::null_mut();
+		};
+
+		let Some(Ok(layout)) = header_size.checked_add(size).map(|total_size| Layout::from_size_align(total_size, alignment)) else {
+			return std::ptr::null_mut();
+		};
+
+		unsafe {
+			let base_ptr = std::alloc::alloc(layout);
+			if base_ptr.is_null() {
+				return std::ptr::null_mut();
+			}
+
+			let user_ptr = base_ptr.add(header_size);
+			user_ptr.cast::<AllocationHeader>().sub(1).write(AllocationHeader { layout, header_size, scope });
+
+			let counters = scope_counters(scope);
+			let allocated_bytes = counters.allocated_bytes.fetch_add(size, Ordering::Relaxed) + size;
+			counters.peak_allocated_bytes.fetch_max(allocated_bytes, Ordering::Relaxed);
+			counters.allocation_count.fetch_add(1, Ordering::Relaxed);
+			counters.total_allocation_count.fetch_add(1, Ordering::Relaxed);
+
+			user_ptr.cast()
+		}
+	}
+
+	unsafe fn header(user_ptr: *mut c_void) -> AllocationHeader {
+		unsafe { user_ptr.cast::<AllocationHeader>().sub(1).read() }
+	}
+
+	fn user_size(header: &AllocationHeader) -> usize {
+		header.layout.size() - header.header_size
+	}
+
+	unsafe fn free(user_ptr: *mut c_void) {
+		if user_ptr.is_null() {
+			return;
+		}
+
+		unsafe {
+			let header = header(user_ptr);
+			let size = user_size(&header);
+
+			let counters = scope_counters(header.scope);
+			counters.allocated_bytes.fetch_sub(size, Ordering::Relaxed);
+			counters.allocation_count.fetch_sub(1, Ordering::Relaxed);
+
+			std::alloc::dealloc(user_ptr.cast::<u8>().sub(header.header_size), header.layout);
+		}
+	}
+
+
+	unsafe extern "system" fn allocation_fn(_: *mut c_void, size: usize, alignment: usize, scope: vk::SystemAllocationScope) -> *mut c_void {
+		unsafe { allocate(size, alignment, scope) }
+	}
+
+	unsafe extern "system" fn reallocation_fn(_: *mut c_void, original: *mut c_void, size: usize, alignment: usize, scope: vk::SystemAllocationScope) -> *mut c_void {
+		unsafe {
+			if original.is_null() {
+				return allocate(size, alignment, scope);
+			}
+
+			if size == 0 {
+				free(original);
+				return std::ptr::null_mut();
+			}
+
+			// Goes through the same overflow checks as a fresh allocation.
+			let new_ptr = allocate(size, alignment, scope);
+			if new_ptr.is_null() {
+				// The original allocation must be left untouched on failure.
+				return new_ptr;
+			}
+
+			let original_size = user_size(&header(original));
+			std::ptr::copy_nonoverlapping(original.cast::<u8>(), new_ptr.cast::<u8>(), original_size.min(size));
+			free(original);
+
+			new_ptr
+		}
+	}
+
+	unsafe extern "system" fn free_fn(_: *mut c_void, memory: *mut c_void) {
+		unsafe { free(memory) }
+	}
+
+	unsafe extern "system" fn internal_allocation_fn(_: *mut c_void, size: usize, _: vk::InternalAllocationType, scope: vk::SystemAllocationScope) {
+		scope_counters(scope).internal_allocated_bytes.fetch_add(size, Ordering::Relaxed);
+	}
+
+	unsafe extern "system" fn internal_free_fn(_: *mut c_void, size: usize, _: vk::InternalAllocationType, scope: vk::SystemAllocationScope) {
+		scope_counters(scope).internal_allocated_bytes.fetch_sub(size, Ordering::Relaxed);
+	}
+}
+
+
+/// Log current and peak host memory usage per allocation scope.
+/// If `check_leaks` is set, anything still allocated is reported as an error - should only be used once the instance is destroyed.
+pub fn report_host_allocations(check_leaks: bool) {
+	let Some(stats) = host_allocation_stats() else {
+		return
+	};
+
+	log::info!("Driver host allocations:");
+
+	for (scope, stats) in TRACKED_ALLOCATION_SCOPES.iter().zip(stats.iter()) {
+		log::info!("--- {scope:?}: {:>6}KiB in {:>5} allocations (peak {}KiB, {} allocations total, {}KiB internal)",
+			stats.allocated_bytes >> 10, stats.allocation_count, stats.peak_allocated_bytes >> 10,
+			stats.total_allocation_count, stats.internal_allocated_bytes >> 10);
+
+		if check_leaks && stats.allocation_count > 0 {
+			log::error!("Driver leaked {} bytes of host memory in {} allocations in scope {scope:?}", stats.allocated_bytes, stats.allocation_count);
+		}
+	}
+}
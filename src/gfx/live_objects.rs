@@ -0,0 +1,82 @@
+use ash::vk;
+use ash::vk::Handle;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::panic::Location;
+
+
+#[derive(Debug, Clone)]
+pub struct LiveObject {
+	pub object_type: vk::ObjectType,
+	pub raw_handle: u64,
+	pub name: String,
+	pub location: &'static Location<'static>,
+}
+
+/// Every Vulkan object created through [`gfx::Core::register_object`](crate::gfx::Core::register_object) that hasn't been destroyed yet.
+/// Objects destroyed through the deletion queue are removed automatically.
+#[derive(Default)]
+pub struct LiveObjectRegistry {
+	objects: RefCell<HashMap<(vk::ObjectType, u64), LiveObject>>,
+}
+
+impl LiveObjectRegistry {
+	pub fn register<H: Handle + Copy>(&self, handle: H, name: &str, location: &'static Location<'static>) {
+		let key = (H::TYPE, handle.as_raw());
+
+		let previous = self.objects.borrow_mut().insert(key, LiveObject {
+			object_type: H::TYPE,
+			raw_handle: handle.as_raw(),
+			name: name.to_owned(),
+			location,
+		});
+
+		if let Some(previous) = previous {
+			log::warn!("{:?} '{}' (0x{:x}) registered twice - previously registered as '{}' at {}",
+				H::TYPE, name, handle.as_raw(), previous.name, previous.location);
+		}
+	}
+
+	/// Unregistering something that was never registered is fine.
+	pub fn unregister<H: Handle + Copy>(&self, handle: H) {
+		self.objects.borrow_mut().remove(&(H::TYPE, handle.as_raw()));
+	}
+
+	#[allow(dead_code)]
+	pub fn len(&self) -> usize {
+		self.objects.borrow().len()
+	}
+
+	#[allow(dead_code)]
+	pub fn is_empty(&self) -> bool {
+		self.objects.borrow().is_empty()
+	}
+
+	/// Live objects ordered by type and then name.
+	pub fn objects(&self) -> Vec<LiveObject> {
+		let mut objects: Vec<_> = self.objects.borrow().values().cloned().collect();
+		objects.sort_by(|a, b| (a.object_type.as_raw(), &a.name).cmp(&(b.object_type.as_raw(), &b.name)));
+		objects
+	}
+
+	/// Log every object that is still alive as an error, grouped by type. Returns the number of leaked objects.
+	pub fn report_leaks(&self) -> usize {
+		let objects = self.objects();
+		if objects.is_empty() {
+			return 0;
+		}
+
+		log::error!("{} Vulkan objects were never destroyed:", objects.len());
+
+		for group in objects.chunk_by(|a, b| a.object_type == b.object_type) {
+			log::error!("--- {:?}: {}", group[0].object_type, group.len());
+
+			for object in group {
+				log::error!("------ '{}' (0x{:x}) created at {}", object.name, object.raw_handle, object.location);
+			}
+		}
+
+		objects.len()
+	}
+}
@@ -0,0 +1,75 @@
+use crate::gfx;
+use ash::vk;
+
+
+/// A Vulkan object that queues its own deletion when dropped, after the last submit that was marked as using it.
+/// Since deletion only ever happens on drop, or through [`Owned::queue_deletion`] which consumes the handle, it can't be deleted twice.
+pub struct Owned<T>
+	where T: Copy + gfx::DeletableResource + 'static
+{
+	// Only None while being dropped or released.
+	resource: Option<T>,
+	deletion_queue: gfx::SharedDeletionQueue,
+	usage_tracker: gfx::UsageTracker,
+}
+
+pub type OwnedImage = Owned<vk::Image>;
+pub type OwnedImageView = Owned<vk::ImageView>;
+#[allow(dead_code)]
+pub type OwnedBuffer = Owned<vk::Buffer>;
+pub type OwnedPipeline = Owned<vk::Pipeline>;
+pub type OwnedMemory = Owned<vk::DeviceMemory>;
+
+impl<T> Owned<T>
+	where T: Copy + gfx::DeletableResource + 'static
+{
+	pub fn new(resource: T, deletion_queue: &gfx::SharedDeletionQueue) -> Owned<T> {
+		Owned {
+			resource: Some(resource),
+			deletion_queue: deletion_queue.clone(),
+			usage_tracker: gfx::UsageTracker::new(),
+		}
+	}
+
+	pub fn handle(&self) -> T {
+		self.resource.unwrap()
+	}
+
+	/// Queue deletion now rather than when dropped. Same as dropping, but more explicit.
+	#[allow(dead_code)]
+	pub fn queue_deletion(self) {
+		drop(self);
+	}
+
+	/// Give up ownership without queueing deletion - the caller becomes responsible for destroying the resource.
+	#[allow(dead_code)]
+	pub fn release(mut self) -> T {
+		self.resource.take().unwrap()
+	}
+}
+
+impl<T> Drop for Owned<T>
+	where T: Copy + gfx::DeletableResource + 'static
+{
+	fn drop(&mut self) {
+		if let Some(resource) = self.resource.take() {
+			self.deletion_queue.queue_deletion_after_use(resource, &self.usage_tracker);
+		}
+	}
+}
+
+impl<T> gfx::TrackedResource for Owned<T>
+	where T: Copy + gfx::DeletableResource + 'static
+{
+	fn usage_tracker(&self) -> &gfx::UsageTracker {
+		&self.usage_tracker
+	}
+}
+
+impl<T> std::fmt::Debug for Owned<T>
+	where T: Copy + gfx::DeletableResource + 'static
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_tuple("Owned").field(&self.resource).finish()
+	}
+}
@@ -3,31 +3,242 @@ use anyhow::Context;
 use winit::window::Window;
 use crate::gfx;
 
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 
-pub struct Frame {
-	vk_cmd_buffer: vk::CommandBuffer,
-	swapchain_image: SwapchainImage,
-	sync_index: usize,
 
+/// A swapchain image acquired for a [`gfx::Frame`], in `ATTACHMENT_OPTIMAL` layout.
+/// It is transitioned for and presented when the frame is submitted.
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceImage {
+	#[allow(dead_code)]
+	pub vk_image: vk::Image,
+	pub vk_image_view: vk::ImageView,
 	pub extent: vk::Extent2D,
+
+	/// Rotation the presentation engine expects to have already been applied to the image.
+	/// Rendering should rotate clip space to match, rather than relying on the compositor to do it.
+	pub transform: vk::SurfaceTransformFlagsKHR,
+
+	/// Anything other than [`vk::ColorSpaceKHR::SRGB_NONLINEAR`] needs the shader to convert and encode its output itself - see [`ColorSpacePreference`].
+	pub color_space: vk::ColorSpaceKHR,
+	pub format: vk::Format,
+
+	/// How the compositor treats alpha written to the image - see [`CompositeAlphaPreference`].
+	pub composite_alpha: vk::CompositeAlphaFlagsKHR,
+}
+
+impl SurfaceImage {
+	/// Size of the image as it appears on screen, once `transform` is applied. Use this for aspect ratios.
+	pub fn display_extent(&self) -> vk::Extent2D {
+		rotate_extent(self.extent, self.transform)
+	}
+
+	/// Whether `vk_image_view` sRGB encodes what is written through it.
+	pub fn has_srgb_view(&self) -> bool {
+		matches!(srgb_view_format(self.format),
+			vk::Format::R8G8B8A8_SRGB | vk::Format::B8G8R8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32)
+	}
+}
+
+/// Shared between a surface and the frames presenting to it, so that the results of presenting are picked up by the next acquire.
+#[derive(Debug, Default)]
+pub struct PresentStatus {
+	// Set when acquire or present reports the swapchain as suboptimal or out of date.
+	needs_recreate: Cell<bool>,
+
+	// Index of an image that was acquired for a frame that hasn't been presented yet.
+	unpresented_image: Cell<Option<u32>>,
+
+	// Set when anything reports VK_ERROR_SURFACE_LOST_KHR. Can only be recovered from with a new surface.
+	lost: Cell<bool>,
 }
 
-impl Frame {
-	pub fn cmd_buffer(&self) -> vk::CommandBuffer {
-		self.vk_cmd_buffer
+impl PresentStatus {
+	pub(super) fn request_recreate(&self) {
+		self.needs_recreate.set(true);
+	}
+
+	pub(super) fn mark_lost(&self) {
+		self.lost.set(true);
+	}
+
+	pub(super) fn mark_presented(&self) {
+		self.unpresented_image.set(None);
+	}
+
+	/// The unpresented image was given back with vkReleaseSwapchainImagesEXT instead.
+	pub(super) fn mark_released(&self) {
+		self.unpresented_image.set(None);
 	}
+}
+
+/// Whether a surface can currently be rendered to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfaceState {
+	Active,
+
+	/// The window is minimised or has no area, so there is no swapchain to render to.
+	/// Becomes active again once resized to a non-zero size.
+	ZeroSize,
+
+	/// The window is hidden, so anything rendered won't be seen. Images can still be acquired.
+	/// Becomes active again with [`PresentableSurface::set_occluded`].
+	Occluded,
+
+	/// The surface is no longer usable, and must be replaced with a new [`PresentableSurface`].
+	Lost,
+}
+
+/// How frames should be paced, in terms of what the user cares about rather than specific present modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresentModePreference {
+	/// No tearing, capped to the display refresh rate. Late frames may tear where supported.
+	Vsync,
+
+	/// No tearing, but frames are never blocked on present - newer frames replace queued ones.
+	LowLatency,
+
+	/// Present as soon as possible, with tearing.
+	Uncapped,
+}
 
-	pub fn swapchain_image_view(&self) -> vk::ImageView {
-		self.swapchain_image.vk_image_view
+impl PresentModePreference {
+	/// Acceptable present modes, most preferred first.
+	fn present_modes(self) -> &'static [vk::PresentModeKHR] {
+		match self {
+			PresentModePreference::Vsync => &[vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO],
+			PresentModePreference::LowLatency => &[vk::PresentModeKHR::MAILBOX],
+			PresentModePreference::Uncapped => &[vk::PresentModeKHR::IMMEDIATE],
+		}
 	}
 }
 
-struct FrameSync {
-	image_available_semaphore: vk::Semaphore,
-	raster_finish_semaphore: vk::Semaphore,
+/// Which colour space to present in. Everything but `Srgb` is opt-in, and only available with VK_EXT_swapchain_colorspace and a surface that supports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpacePreference {
+	/// SDR with 8-bit formats. The swapchain image view does the sRGB encoding where possible.
+	Srgb,
+
+	/// Rec.2020 primaries with the ST.2084 (PQ) transfer function, in a 10-bit or FP16 format.
+	Hdr10,
 
-	prev_submit_timeline_value: u64,
+	/// Linear with Rec.709 primaries in FP16, where 1.0 is 80 nits. Values can go above 1.0 and below 0.0.
+	ScRgb,
+
+	/// Wide gamut SDR - Display P3 primaries with the sRGB transfer function, in a 10-bit or FP16 format.
+	DisplayP3,
+}
+
+impl ColorSpacePreference {
+	fn color_space(self) -> vk::ColorSpaceKHR {
+		match self {
+			ColorSpacePreference::Srgb => vk::ColorSpaceKHR::SRGB_NONLINEAR,
+			ColorSpacePreference::Hdr10 => vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+			ColorSpacePreference::ScRgb => vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+			ColorSpacePreference::DisplayP3 => vk::ColorSpaceKHR::DISPLAY_P3_NONLINEAR_EXT,
+		}
+	}
+
+	/// Acceptable formats, most preferred first.
+	fn formats(self) -> &'static [vk::Format] {
+		match self {
+			ColorSpacePreference::Srgb => &[
+				vk::Format::R8G8B8A8_SRGB,
+				vk::Format::B8G8R8A8_SRGB,
+				vk::Format::A8B8G8R8_SRGB_PACK32,
+
+				vk::Format::R8G8B8A8_UNORM,
+				vk::Format::B8G8R8A8_UNORM,
+				vk::Format::A8B8G8R8_UNORM_PACK32,
+			],
+
+			ColorSpacePreference::Hdr10 | ColorSpacePreference::DisplayP3 => &[
+				vk::Format::A2B10G10R10_UNORM_PACK32,
+				vk::Format::A2R10G10B10_UNORM_PACK32,
+				vk::Format::R16G16B16A16_SFLOAT,
+			],
+
+			ColorSpacePreference::ScRgb => &[vk::Format::R16G16B16A16_SFLOAT],
+		}
+	}
+}
+
+/// How the window should be blended with whatever is behind it.
+/// Anything but `Opaque` needs the window to be created as transparent, and is only available where the surface supports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompositeAlphaPreference {
+	/// Alpha is ignored.
+	Opaque,
+
+	/// Colour has already been multiplied by alpha.
+	PreMultiplied,
+
+	/// Colour is multiplied by alpha by the compositor.
+	PostMultiplied,
+}
+
+impl CompositeAlphaPreference {
+	fn composite_alpha(self) -> vk::CompositeAlphaFlagsKHR {
+		match self {
+			CompositeAlphaPreference::Opaque => vk::CompositeAlphaFlagsKHR::OPAQUE,
+			CompositeAlphaPreference::PreMultiplied => vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+			CompositeAlphaPreference::PostMultiplied => vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+		}
+	}
+}
+
+/// Pick the first supported composite alpha mode out of `preferences`.
+/// Falls back to opaque, then to whatever the surface does support, since not every platform supports opaque.
+fn select_composite_alpha(preferences: &[CompositeAlphaPreference], supported: vk::CompositeAlphaFlagsKHR) -> vk::CompositeAlphaFlagsKHR {
+	let fallbacks = [
+		vk::CompositeAlphaFlagsKHR::OPAQUE,
+		vk::CompositeAlphaFlagsKHR::INHERIT,
+		vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+		vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+	];
+
+	preferences.iter()
+		.map(|preference| preference.composite_alpha())
+		.chain(fallbacks)
+		.find(|&composite_alpha| supported.contains(composite_alpha))
+		.unwrap_or(vk::CompositeAlphaFlagsKHR::OPAQUE)
+}
+
+/// Pick the first supported format and colour space out of `preferences`, falling back to sRGB.
+fn select_surface_format(preferences: &[ColorSpacePreference], supported_formats: &[vk::SurfaceFormatKHR]) -> Option<vk::SurfaceFormatKHR> {
+	preferences.iter()
+		.chain([&ColorSpacePreference::Srgb])
+		.flat_map(|preference| {
+			let color_space = preference.color_space();
+			preference.formats().iter()
+				.map(move |&format| vk::SurfaceFormatKHR { format, color_space })
+		})
+		.find(|surface_format| supported_formats.contains(surface_format))
+}
+
+/// Mastering display metadata describing a typical HDR10 display - Rec.2020 primaries, D65 white point and 1000 nit peak.
+pub fn default_hdr_metadata() -> vk::HdrMetadataEXT<'static> {
+	vk::HdrMetadataEXT::default()
+		.display_primary_red(vk::XYColorEXT { x: 0.708, y: 0.292 })
+		.display_primary_green(vk::XYColorEXT { x: 0.170, y: 0.797 })
+		.display_primary_blue(vk::XYColorEXT { x: 0.131, y: 0.046 })
+		.white_point(vk::XYColorEXT { x: 0.3127, y: 0.3290 })
+		.max_luminance(1000.0)
+		.min_luminance(0.001)
+		.max_content_light_level(1000.0)
+		.max_frame_average_light_level(400.0)
+}
+
+/// Pick the first supported present mode out of `preferences`, falling back to FIFO, which is always supported.
+fn select_present_mode(preferences: &[PresentModePreference], supported_present_modes: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
+	preferences.iter()
+		.flat_map(|preference| preference.present_modes())
+		.find(|mode| supported_present_modes.contains(mode))
+		.copied()
+		.unwrap_or(vk::PresentModeKHR::FIFO)
 }
 
 
@@ -36,70 +247,77 @@ pub struct PresentableSurface {
 
 	swapchain: Swapchain,
 
-	frame_syncs: Vec<FrameSync>,
-	next_sync_index: usize,
+	// One per frame in flight, indexed by [`gfx::Frame::slot_index`].
+	image_available_semaphores: Vec<vk::Semaphore>,
 
-	vk_cmd_buffers: Vec<vk::CommandBuffer>,
+	// Covers the image available semaphores. Swapchains have their own.
+	usage_tracker: gfx::UsageTracker,
 
 	pub swapchain_extent: vk::Extent2D,
 	pub swapchain_format: vk::Format,
+	pub swapchain_color_space: vk::ColorSpaceKHR,
+	pub swapchain_transform: vk::SurfaceTransformFlagsKHR,
+	pub swapchain_composite_alpha: vk::CompositeAlphaFlagsKHR,
+	hdr_metadata: Option<vk::HdrMetadataEXT<'static>>,
 	swapchain_present_mode: vk::PresentModeKHR,
+	supported_present_modes: Vec<vk::PresentModeKHR>,
 	num_swapchain_images: u32,
+
+	// Last window size passed to resize, for surfaces that don't report a current extent.
+	requested_extent: vk::Extent2D,
+
+	status: Rc<PresentStatus>,
+
+	// Smoothed time between presents reaching the display, measured by wait_for_present.
+	present_interval: Option<Duration>,
+
+	occluded: bool,
 }
 
 impl PresentableSurface {
-	pub fn new(core: &gfx::Core, window: &Window) -> anyhow::Result<PresentableSurface> {
+	/// `present_mode_preferences` are tried in order - see [`PresentableSurface::set_present_mode`].
+	/// `frames_in_flight` must match the [`gfx::FrameContext`] that frames presenting to this surface come from.
+	/// `color_space_preferences` are tried in order, falling back to sRGB.
+	/// `composite_alpha_preferences` are tried in order, falling back to opaque.
+	pub fn new(core: &gfx::Core, window: &Window, present_mode_preferences: &[PresentModePreference], frames_in_flight: usize, color_space_preferences: &[ColorSpacePreference], composite_alpha_preferences: &[CompositeAlphaPreference]) -> anyhow::Result<PresentableSurface> {
+		anyhow::ensure!(frames_in_flight > 0, "Need at least one frame in flight");
+
+		#[allow(clippy::needless_borrows_for_generic_args)]
 		let vk_surface = core.create_surface(&window)?;
+		core.register_object(vk_surface, "Window Surface");
 
 		// Swapchain
 		let surface_capabilities = core.get_surface_capabilities(vk_surface)?;
 		let supported_formats = unsafe{ core.surface_fns.get_physical_device_surface_formats(core.vk_physical_device, vk_surface)? };
 		let supported_present_modes = unsafe{ core.surface_fns.get_physical_device_surface_present_modes(core.vk_physical_device, vk_surface)? };
 
-		const NO_CURRENT_EXTENT: vk::Extent2D = vk::Extent2D{ width: u32::MAX, height: u32::MAX };
-		let swapchain_extent = match surface_capabilities.current_extent {
-			NO_CURRENT_EXTENT => {
-				let (width, height) = window.inner_size().into();
-				vk::Extent2D{ width, height }
-			},
-
-			current => current,
+		let window_extent = {
+			let (width, height) = window.inner_size().into();
+			vk::Extent2D{ width, height }
 		};
 
+		let swapchain_transform = select_pre_transform(&surface_capabilities);
+		let swapchain_extent = swapchain_image_extent(&surface_capabilities, window_extent);
+
 		log::info!("Surface capabilities: {surface_capabilities:#?}");
 		log::info!("Supported formats: {supported_formats:#?}");
 		log::info!("Supported present modes: {supported_present_modes:?}");
 
-		anyhow::ensure!(
-			surface_capabilities.supported_transforms.contains(vk::SurfaceTransformFlagsKHR::IDENTITY),
-			"Can't present to surface - identity transform not supported");
-
 		dbg!(&surface_capabilities, &supported_formats, &supported_present_modes);
 
-		let selected_present_mode = supported_present_modes.into_iter()
-			.max_by_key(|&mode| match mode {
-				vk::PresentModeKHR::FIFO => 1,
-				vk::PresentModeKHR::FIFO_RELAXED => 2,
-				// vk::PresentModeKHR::MAILBOX => 10,
-				_ => 0,
-			})
-			.context("Selecting supported present mode")?;
-
-		let selected_format = supported_formats.into_iter()
-			.filter(|format| format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR)
-			.map(|format| format.format)
-			.max_by_key(|&format| match format {
-				vk::Format::R8G8B8A8_SRGB => 15,
-				vk::Format::B8G8R8A8_SRGB => 14,
-				vk::Format::A8B8G8R8_SRGB_PACK32 => 13,
-
-				vk::Format::R8G8B8A8_UNORM => 5,
-				vk::Format::B8G8R8A8_UNORM => 4,
-				vk::Format::A8B8G8R8_UNORM_PACK32 => 3,
-				_ => 0,
-			})
+		let selected_present_mode = select_present_mode(present_mode_preferences, &supported_present_modes);
+
+		let selected_format = select_surface_format(color_space_preferences, &supported_formats)
 			.context("Selecting supported swapchain format")?;
 
+		let selected_composite_alpha = select_composite_alpha(composite_alpha_preferences, surface_capabilities.supported_composite_alpha);
+
+		// Only meaningful for colour spaces that can go beyond SDR.
+		let hdr_metadata = match selected_format.color_space {
+			vk::ColorSpaceKHR::HDR10_ST2084_EXT | vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT if core.supports_hdr_metadata => Some(default_hdr_metadata()),
+			_ => None,
+		};
+
 		let max_images = match surface_capabilities.max_image_count {
 			0 => u32::MAX,
 			n => n
@@ -109,72 +327,119 @@ impl PresentableSurface {
 
 		log::info!("Selected present mode: {selected_present_mode:?}");
 		log::info!("Selected swapchain format: {selected_format:?}");
-
-		let swapchain = Swapchain::new(core, vk_surface, selected_format, selected_present_mode, swapchain_extent, num_images, None)?;
-
-		// command buffers
-		let vk_cmd_buffers = unsafe {
-			let create_info = vk::CommandBufferAllocateInfo::default()
-				.command_buffer_count(swapchain.vk_images.len() as u32)
-				.command_pool(core.vk_cmd_pool)
-				.level(vk::CommandBufferLevel::PRIMARY);
-
-			core.vk_device.allocate_command_buffers(&create_info)?
+		log::info!("Selected pre-transform: {swapchain_transform:?}");
+		log::info!("Selected composite alpha: {selected_composite_alpha:?}");
+
+		let swapchain_config = SwapchainConfig {
+			surface_format: selected_format,
+			present_mode: selected_present_mode,
+			extent: swapchain_extent,
+			num_images,
+			pre_transform: swapchain_transform,
+			composite_alpha: selected_composite_alpha,
 		};
 
-		let frame_syncs = (0..swapchain.vk_images.len())
+		let swapchain = Swapchain::new(core, vk_surface, &swapchain_config, None)?;
+		swapchain.set_hdr_metadata(core, hdr_metadata.as_ref());
+
+		let image_available_semaphores = (0..frames_in_flight)
 			.map(|_| unsafe {
-				anyhow::Result::Ok(FrameSync {
-					image_available_semaphore: core.vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?,
-					raster_finish_semaphore: core.vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?,
-					prev_submit_timeline_value: 0,
-				})
+				core.vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), core.allocation_callbacks())
 			})
-			.collect::<anyhow::Result<Vec<_>>>()?;
+			.collect::<Result<Vec<_>, _>>()?;
+
+		for &semaphore in image_available_semaphores.iter() {
+			core.register_object(semaphore, "Image Available Semaphore");
+		}
+
+		log::info!("Frames in flight: {frames_in_flight}, swapchain images: {}", swapchain.vk_images.len());
 
 		Ok(PresentableSurface {
 			vk_surface,
 
 			swapchain,
 
-			frame_syncs,
-			next_sync_index: 0,
-
-			vk_cmd_buffers,
+			image_available_semaphores,
+			usage_tracker: gfx::UsageTracker::new(),
 
 			swapchain_extent,
-			swapchain_format: selected_format,
+			swapchain_format: selected_format.format,
+			swapchain_color_space: selected_format.color_space,
+			swapchain_transform,
+			swapchain_composite_alpha: selected_composite_alpha,
+			hdr_metadata,
 			swapchain_present_mode: selected_present_mode,
+			supported_present_modes,
 			num_swapchain_images: num_images,
+
+			requested_extent: window_extent,
+			status: Rc::default(),
+
+			present_interval: None,
+
+			occluded: false,
 		})
 	}
 
 	pub fn queue_deletion(self, deletion_queue: &mut gfx::DeletionQueue) {
-		let latest_submit_timeline_value = self.frame_syncs.iter()
-			.map(|sync| sync.prev_submit_timeline_value)
-			.max()
-			.unwrap_or(0);
-
-		for frame_sync in self.frame_syncs {
-			deletion_queue.queue_deletion_after(frame_sync.image_available_semaphore, frame_sync.prev_submit_timeline_value);
-			deletion_queue.queue_deletion_after(frame_sync.raster_finish_semaphore, frame_sync.prev_submit_timeline_value);
+		for semaphore in self.image_available_semaphores {
+			deletion_queue.queue_deletion_after_use(semaphore, &self.usage_tracker);
 		}
 
-		self.swapchain.queue_deletion(deletion_queue, latest_submit_timeline_value);
+		self.swapchain.queue_deletion(deletion_queue);
 
-		// The surface must be deleted _after_ the swapchain
-		deletion_queue.queue_deletion_after(self.vk_surface, latest_submit_timeline_value+1);
+		// The surface must be deleted _after_ the swapchain, so is queued behind it at the same timeline value.
+		match &self.swapchain.present_fences {
+			// Swapchains retired by earlier recreates may still be waiting on their own present fences too.
+			Some(_) => {
+				let swapchain_last_used = self.swapchain.usage_tracker.last_used_timeline_value();
+				deletion_queue.queue_deletion_after_fenced_deletions(self.vk_surface, swapchain_last_used);
+			}
+
+			None => {
+				let swapchain_last_used = deletion_queue.timeline_value_after_use(&self.swapchain.usage_tracker);
+				deletion_queue.queue_deletion_after(self.vk_surface, swapchain_last_used);
+			}
+		}
 	}
 
+	/// `new_size` is the size of the window, which may be rotated relative to the swapchain images.
 	pub fn resize(&mut self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue, new_size: vk::Extent2D) -> anyhow::Result<()> {
-		if self.swapchain_extent == new_size {
+		self.requested_extent = new_size;
+
+		let surface_capabilities = core.get_surface_capabilities(self.vk_surface)?;
+		let image_extent = swapchain_image_extent(&surface_capabilities, new_size);
+		let pre_transform = select_pre_transform(&surface_capabilities);
+
+		if self.swapchain_extent == image_extent && self.swapchain_transform == pre_transform {
 			return Ok(());
 		}
 
 		log::info!("Resize event {new_size:?}");
 
+		self.recreate_swapchain(core, deletion_queue, &surface_capabilities)
+	}
+
+	/// Recreate the swapchain to match the surface's current size and transform.
+	fn recreate_from_surface(&mut self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue) -> anyhow::Result<()> {
 		let surface_capabilities = core.get_surface_capabilities(self.vk_surface)?;
 
+		log::info!("Recreating out of date swapchain");
+
+		self.recreate_swapchain(core, deletion_queue, &surface_capabilities)
+	}
+
+	fn recreate_swapchain(&mut self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue, surface_capabilities: &vk::SurfaceCapabilitiesKHR) -> anyhow::Result<()> {
+		self.status.needs_recreate.set(false);
+
+		let new_size = swapchain_image_extent(surface_capabilities, self.requested_extent);
+		self.swapchain_transform = select_pre_transform(surface_capabilities);
+
+		// Without swapchain_maintenance1 there's no way to give an acquired image back, so it stays acquired until the swapchain is destroyed.
+		if let Some(image_index) = self.status.unpresented_image.take() {
+			self.swapchain.release_images(core, &[image_index])?;
+		}
+
 		if new_size.width < surface_capabilities.min_image_extent.width
 			|| new_size.width > surface_capabilities.max_image_extent.width
 			|| new_size.width == 0
@@ -182,51 +447,263 @@ impl PresentableSurface {
 			|| new_size.height > surface_capabilities.max_image_extent.height
 			|| new_size.height == 0
 		{
-			// TODO(pat.m): skip rendering
+			// The old swapchain is kept around until there's something to replace it with - see SurfaceState::ZeroSize.
 			self.swapchain_extent = vk::Extent2D{ width: 0, height: 0 };
 			return Ok(());
 		}
 
-		let new_swapchain = Swapchain::new(core, self.vk_surface, self.swapchain_format, self.swapchain_present_mode, new_size, self.num_swapchain_images, Some(&self.swapchain))?;
+		let new_swapchain = Swapchain::new(core, self.vk_surface, &self.swapchain_config(new_size), Some(&self.swapchain))?;
+		new_swapchain.set_hdr_metadata(core, self.hdr_metadata.as_ref());
 
 		self.swapchain_extent = new_size;
-		self.swapchain.queue_deletion(deletion_queue, core.timeline_value.get());
+		self.swapchain.queue_deletion(deletion_queue);
 
 		self.swapchain = new_swapchain;
 
 		Ok(())
 	}
 
-	pub fn start_frame(&mut self, core: &gfx::Core) -> anyhow::Result<Frame> {
+	pub fn state(&self) -> SurfaceState {
+		if self.status.lost.get() {
+			SurfaceState::Lost
+		} else if self.swapchain_extent.width == 0 || self.swapchain_extent.height == 0 {
+			SurfaceState::ZeroSize
+		} else if self.occluded {
+			SurfaceState::Occluded
+		} else {
+			SurfaceState::Active
+		}
+	}
+
+	/// Track whether the window is hidden. The swapchain is recreated once it becomes visible again,
+	/// since it may have been invalidated while hidden without anything reporting it.
+	pub fn set_occluded(&mut self, occluded: bool) {
+		if self.occluded && !occluded {
+			self.status.request_recreate();
+		}
+
+		self.occluded = occluded;
+	}
+
+	fn swapchain_config(&self, extent: vk::Extent2D) -> SwapchainConfig {
+		SwapchainConfig {
+			surface_format: vk::SurfaceFormatKHR {
+				format: self.swapchain_format,
+				color_space: self.swapchain_color_space,
+			},
+			present_mode: self.swapchain_present_mode,
+			extent,
+			num_images: self.num_swapchain_images,
+			pre_transform: self.swapchain_transform,
+			composite_alpha: self.swapchain_composite_alpha,
+		}
+	}
+
+	pub fn vk_surface(&self) -> vk::SurfaceKHR {
+		self.vk_surface
+	}
+
+	pub fn color_space(&self) -> vk::ColorSpaceKHR {
+		self.swapchain_color_space
+	}
+
+	pub fn composite_alpha(&self) -> vk::CompositeAlphaFlagsKHR {
+		self.swapchain_composite_alpha
+	}
+
+	/// Describe the content being presented to the display. Only has an effect with VK_EXT_hdr_metadata, and an HDR colour space.
+	/// Applied to the current swapchain and any it is recreated as.
+	#[allow(dead_code)]
+	pub fn set_hdr_metadata(&mut self, core: &gfx::Core, metadata: vk::HdrMetadataEXT<'static>) {
+		self.hdr_metadata = Some(metadata);
+		self.swapchain.set_hdr_metadata(core, self.hdr_metadata.as_ref());
+	}
+
+	#[allow(dead_code)]
+	pub fn present_mode(&self) -> vk::PresentModeKHR {
+		self.swapchain_present_mode
+	}
+
+	/// Block until at most `max_pending_presents` presents to this surface are yet to reach the display,
+	/// so that the CPU doesn't run further ahead of what is on screen than that.
+	/// Does nothing without VK_KHR_present_wait.
+	pub fn wait_for_present(&mut self, core: &gfx::Core, max_pending_presents: u64) -> anyhow::Result<()> {
+		let Some(present_wait_fns) = &core.present_wait_fns else {
+			return Ok(());
+		};
+
+		let target_present_id = self.swapchain.last_present_id.saturating_sub(max_pending_presents);
+		if target_present_id <= self.swapchain.last_waited_present_id {
+			return Ok(());
+		}
+
+		let timeout_ns = 1000*1000*1000;
+
+		// Poll first, since only a wait that actually blocks returns as the present reaches the display.
+		let already_presented = unsafe {
+			present_wait_fns.wait_for_present(self.swapchain.vk_swapchain, target_present_id, 0) == Ok(())
+		};
+
+		let result = match already_presented {
+			true => Ok(()),
+			false => unsafe { present_wait_fns.wait_for_present(self.swapchain.vk_swapchain, target_present_id, timeout_ns) },
+		};
+
+		match result {
+			Ok(()) => {}
+
+			// Presents can be discarded without ever being displayed, so don't treat this as fatal.
+			Err(vk::Result::TIMEOUT) => {
+				log::warn!("Timed out waiting for present {target_present_id}");
+				return Ok(());
+			}
+
+			Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+				self.status.request_recreate();
+				return Ok(());
+			}
+
+			Err(vk::Result::ERROR_SURFACE_LOST_KHR) => {
+				self.status.mark_lost();
+				return Ok(());
+			}
+
+			Err(error) => return Err(error).context("Waiting for present"),
+		}
+
+		self.swapchain.last_waited_present_id = target_present_id;
+
+		// Only known to have been displayed some time before now, so there's nothing to time the next present against.
+		if already_presented {
+			self.swapchain.last_waited_present_time = None;
+			return Ok(());
+		}
+
+		let now = Instant::now();
+
+		if let Some((prev_present_id, prev_time)) = self.swapchain.last_waited_present_time {
+			let sample = (now - prev_time) / (target_present_id - prev_present_id) as u32;
+
+			self.present_interval = Some(match self.present_interval {
+				Some(interval) => interval.mul_f32(0.9) + sample.mul_f32(0.1),
+				None => sample,
+			});
+		}
+
+		self.swapchain.last_waited_present_time = Some((target_present_id, now));
+
+		Ok(())
+	}
+
+	/// Measured time between presents reaching the display, smoothed over recent frames.
+	/// Only available with VK_KHR_present_wait, and only sampled from consecutive [`PresentableSurface::wait_for_present`] calls that had to block,
+	/// since a CPU-bound app never catches up with the display and so never observes when presents land.
+	pub fn present_interval(&self) -> Option<Duration> {
+		self.present_interval
+	}
+
+	/// Switch to the first supported present mode in `preferences`, recreating the swapchain if it changes.
+	/// Falls back to FIFO if none are supported. Returns the selected present mode.
+	pub fn set_present_mode(&mut self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue, preferences: &[PresentModePreference]) -> anyhow::Result<vk::PresentModeKHR> {
+		let present_mode = select_present_mode(preferences, &self.supported_present_modes);
+		if present_mode == self.swapchain_present_mode {
+			return Ok(present_mode);
+		}
+
+		log::info!("Switching present mode {:?} -> {present_mode:?}", self.swapchain_present_mode);
+
+		// No usable swapchain right now - the new mode will be picked up when it's next recreated.
+		if self.swapchain_extent.width == 0 || self.swapchain_extent.height == 0 {
+			self.swapchain_present_mode = present_mode;
+			return Ok(present_mode);
+		}
+
+		let swapchain_config = SwapchainConfig {
+			present_mode,
+			..self.swapchain_config(self.swapchain_extent)
+		};
+
+		let new_swapchain = Swapchain::new(core, self.vk_surface, &swapchain_config, Some(&self.swapchain))?;
+		new_swapchain.set_hdr_metadata(core, self.hdr_metadata.as_ref());
+
+		self.swapchain.queue_deletion(deletion_queue);
+		self.swapchain = new_swapchain;
+		self.swapchain_present_mode = present_mode;
+
+		Ok(present_mode)
+	}
+
+	/// Acquire an image to render to in `frame`, which will be presented when the frame is submitted.
+	/// Recreates the swapchain first if the last acquire or present reported it as suboptimal or out of date.
+	pub fn acquire_image(&mut self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue, frame: &mut gfx::Frame) -> anyhow::Result<SurfaceImage> {
+		anyhow::ensure!(!self.status.lost.get(), "Surface lost");
+
+		if self.status.needs_recreate.get() {
+			self.recreate_from_surface(core, deletion_queue)?;
+		}
+
 		if self.swapchain_extent.width == 0 || self.swapchain_extent.height == 0 {
 			anyhow::bail!("No swapchain");
 		}
 
+		anyhow::ensure!(self.status.unpresented_image.get().is_none(), "Surface already has an image acquired for a frame");
+
+		// The frame's slot being free means the last submit waiting on this semaphore has completed.
+		let image_available_semaphore = *self.image_available_semaphores.get(frame.slot_index())
+			.context("Frame slot out of range - surface created with fewer frames in flight than the FrameContext")?;
+
 		let timeout_ns = 1000*1000*1000;
 
-		let sync_index = self.next_sync_index;
-		self.next_sync_index = (self.next_sync_index + 1) % self.frame_syncs.len();
+		let swapchain_image = match self.swapchain.acquire_image(core, image_available_semaphore, timeout_ns)? {
+			AcquireResult::Acquired{ image, suboptimal } => {
+				// The image is still presentable, so use it and recreate before the next frame.
+				if suboptimal {
+					self.status.request_recreate();
+				}
+
+				image
+			}
+
+			AcquireResult::SurfaceLost => {
+				self.status.mark_lost();
+				anyhow::bail!("Surface lost");
+			}
+
+			// Nothing was acquired so the semaphore is still unsignalled and can be reused.
+			AcquireResult::OutOfDate => {
+				self.recreate_from_surface(core, deletion_queue)?;
+
+				if self.swapchain_extent.width == 0 || self.swapchain_extent.height == 0 {
+					anyhow::bail!("No swapchain");
+				}
+
+				match self.swapchain.acquire_image(core, image_available_semaphore, timeout_ns)? {
+					AcquireResult::Acquired{ image, suboptimal } => {
+						if suboptimal {
+							self.status.request_recreate();
+						}
+
+						image
+					}
 
-		let frame_sync = &self.frame_syncs[sync_index];
-		let vk_cmd_buffer = self.vk_cmd_buffers[sync_index];
+					AcquireResult::OutOfDate => {
+						self.status.request_recreate();
+						anyhow::bail!("Swapchain out of date immediately after being recreated");
+					}
 
-		unsafe {
-			core.vk_device.wait_semaphores(
-				&vk::SemaphoreWaitInfo::default()
-					.semaphores(&[core.vk_timeline_semaphore])
-					.values(&[frame_sync.prev_submit_timeline_value]),
-				timeout_ns
-			)?;
-		}
+					AcquireResult::SurfaceLost => {
+						self.status.mark_lost();
+						anyhow::bail!("Surface lost");
+					}
+				}
+			}
+		};
 
-		let swapchain_image = self.swapchain.acquire_image(core, frame_sync.image_available_semaphore, timeout_ns)?;
+		self.status.unpresented_image.set(Some(swapchain_image.image_index));
 
 		unsafe {
-			core.vk_device.begin_command_buffer(vk_cmd_buffer,
-				&vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT))?;
-
 			core.vk_device.cmd_pipeline_barrier2(
-				vk_cmd_buffer,
+				frame.cmd_buffer(),
 				&vk::DependencyInfo::default()
 					.image_memory_barriers(&[
 						vk::ImageMemoryBarrier2::default()
@@ -250,91 +727,165 @@ impl PresentableSurface {
 					]
 				)
 			);
-
 		}
 
-		Ok(Frame {
-			vk_cmd_buffer,
-			swapchain_image,
-			sync_index,
+		// image available happens-before wait operation, which happens-before any raster output.
+		// i.e., don't block anything except raster while sema is unsignalled
+		frame.wait_for_semaphore(image_available_semaphore, 0, vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT);
+
+		frame.mark_used(&self.usage_tracker);
+		frame.mark_used(&self.swapchain.usage_tracker);
 
+		let present_id = match core.supports_present_wait {
+			true => {
+				self.swapchain.last_present_id += 1;
+				Some(self.swapchain.last_present_id)
+			}
+
+			false => None,
+		};
+
+		frame.add_present(gfx::FramePresent {
+			vk_swapchain: self.swapchain.vk_swapchain,
+			vk_image: swapchain_image.vk_image,
+			image_index: swapchain_image.image_index,
+			present_semaphore: swapchain_image.present_semaphore,
+			present_fence: self.swapchain.present_fences.as_ref()
+				.map(|fences| fences[swapchain_image.image_index as usize]),
+			present_id,
+
+			status: self.status.clone(),
+		});
+
+		Ok(SurfaceImage {
+			vk_image: swapchain_image.vk_image,
+			vk_image_view: swapchain_image.vk_image_view,
 			extent: self.swapchain_extent,
+			transform: self.swapchain_transform,
+			color_space: self.swapchain_color_space,
+			format: self.swapchain_format,
+			composite_alpha: self.swapchain_composite_alpha,
 		})
 	}
+}
 
-	pub fn submit_frame(&mut self, core: &gfx::Core, frame: Frame) -> anyhow::Result<()> {
-		let frame_sync = &mut self.frame_syncs[frame.sync_index];
-		let swapchain_image = frame.swapchain_image;
 
-		unsafe {
-			core.vk_device.cmd_pipeline_barrier2(
-				frame.vk_cmd_buffer,
-				&vk::DependencyInfo::default()
-					.image_memory_barriers(&[
-						vk::ImageMemoryBarrier2::default()
-							.image(swapchain_image.vk_image)
-							.old_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
-							.new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+/// Format swapchain image views are created with - the sRGB equivalent of `format` if there is one.
+fn srgb_view_format(format: vk::Format) -> vk::Format {
+	match format {
+		vk::Format::R8G8B8A8_UNORM => vk::Format::R8G8B8A8_SRGB,
+		vk::Format::B8G8R8A8_UNORM => vk::Format::B8G8R8A8_SRGB,
+		vk::Format::A8B8G8R8_UNORM_PACK32 => vk::Format::A8B8G8R8_SRGB_PACK32,
+		x => x,
+	}
+}
 
-							.src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-							.src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+/// With swapchain_maintenance1, ask for images to be presented unscaled and anchored to the top left when they don't match the surface size, e.g., mid-resize.
+/// Falls back to stretching while keeping the aspect ratio. None if the surface doesn't support either, or without swapchain_maintenance1.
+fn select_present_scaling(core: &gfx::Core, surface: vk::SurfaceKHR, present_mode: vk::PresentModeKHR) -> anyhow::Result<Option<vk::SwapchainPresentScalingCreateInfoEXT<'static>>> {
+	let Some(surface_capabilities2_fns) = &core.surface_capabilities2_fns else {
+		return Ok(None);
+	};
 
-							// Don't wait for anything, vkQueuePresentKHR performs visibility operations automatically
-							.dst_stage_mask(vk::PipelineStageFlags2::NONE)
-							.dst_access_mask(vk::AccessFlags2::NONE)
-							.subresource_range(
-								vk::ImageSubresourceRange::default()
-									.aspect_mask(vk::ImageAspectFlags::COLOR)
-									.base_mip_level(0)
-									.base_array_layer(0)
-									.level_count(1)
-									.layer_count(1)
-							)
-					]
-				)
-			);
+	let mut present_mode_info = vk::SurfacePresentModeEXT::default()
+		.present_mode(present_mode);
 
-			core.vk_device.end_command_buffer(frame.vk_cmd_buffer)?;
-
-			let timeline_value = core.next_timeline_value();
-			frame_sync.prev_submit_timeline_value = timeline_value;
-
-			core.vk_device.queue_submit2(
-				core.vk_queue,
-				&[
-					vk::SubmitInfo2::default()
-						.wait_semaphore_infos(&[
-							// image available happens-before wait operation, which happens-before any raster output.
-							// i.e., don't block anything except raster while sema is unsignalled
-							vk::SemaphoreSubmitInfo::default()
-								.semaphore(frame_sync.image_available_semaphore)
-								.stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-						])
-						.command_buffer_infos(&[
-							vk::CommandBufferSubmitInfo::default()
-								.command_buffer(frame.vk_cmd_buffer)
-						])
-						.signal_semaphore_infos(&[
-							// raster output happens-before 'raster finish sema' signal operation, which happens-before later present.
-							vk::SemaphoreSubmitInfo::default()
-								.semaphore(frame_sync.raster_finish_semaphore)
-								.stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT),
-
-							// timeline semaphore signal op happens-after all commands complete, which happens-before the next frame where images and cmd buffers can be reused.
-							vk::SemaphoreSubmitInfo::default()
-								.semaphore(core.vk_timeline_semaphore)
-								.stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
-								.value(timeline_value),
-						])
-				],
-				vk::Fence::null()
-			).context("Submitting command buffer")?;
-		}
+	let surface_info = vk::PhysicalDeviceSurfaceInfo2KHR::default()
+		.surface(surface)
+		.push_next(&mut present_mode_info);
 
-		self.swapchain.submit_image(core, swapchain_image, frame_sync.raster_finish_semaphore)?;
+	let mut scaling_capabilities = vk::SurfacePresentScalingCapabilitiesEXT::default();
+	let mut capabilities = vk::SurfaceCapabilities2KHR::default()
+		.push_next(&mut scaling_capabilities);
 
-		Ok(())
+	unsafe {
+		surface_capabilities2_fns.get_physical_device_surface_capabilities2(core.vk_physical_device, &surface_info, &mut capabilities)
+			.context("Querying surface present scaling capabilities")?;
 	}
+
+	let supported_scaling = scaling_capabilities.supported_present_scaling;
+	let scaling = [vk::PresentScalingFlagsEXT::ONE_TO_ONE, vk::PresentScalingFlagsEXT::ASPECT_RATIO_STRETCH].into_iter()
+		.find(|&scaling| supported_scaling.contains(scaling));
+
+	let select_gravity = |supported: vk::PresentGravityFlagsEXT| {
+		[vk::PresentGravityFlagsEXT::MIN, vk::PresentGravityFlagsEXT::CENTERED].into_iter()
+			.find(|&gravity| supported.contains(gravity))
+	};
+
+	let gravity_x = select_gravity(scaling_capabilities.supported_present_gravity_x);
+	let gravity_y = select_gravity(scaling_capabilities.supported_present_gravity_y);
+
+	let (Some(scaling), Some(gravity_x), Some(gravity_y)) = (scaling, gravity_x, gravity_y) else {
+		return Ok(None);
+	};
+
+	Ok(Some(
+		vk::SwapchainPresentScalingCreateInfoEXT::default()
+			.scaling_behavior(scaling)
+			.present_gravity_x(gravity_x)
+			.present_gravity_y(gravity_y)
+	))
+}
+
+
+const NO_CURRENT_EXTENT: vk::Extent2D = vk::Extent2D{ width: u32::MAX, height: u32::MAX };
+
+/// Adopt whatever transform the presentation engine is currently using, so that it never has to rotate images itself.
+fn select_pre_transform(surface_capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::SurfaceTransformFlagsKHR {
+	let current_transform = surface_capabilities.current_transform;
+
+	if surface_capabilities.supported_transforms.contains(current_transform) || !surface_capabilities.supported_transforms.contains(vk::SurfaceTransformFlagsKHR::IDENTITY) {
+		current_transform
+	} else {
+		vk::SurfaceTransformFlagsKHR::IDENTITY
+	}
+}
+
+fn is_rotated_90(transform: vk::SurfaceTransformFlagsKHR) -> bool {
+	transform.intersects(
+		vk::SurfaceTransformFlagsKHR::ROTATE_90
+		| vk::SurfaceTransformFlagsKHR::ROTATE_270
+		| vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_90
+		| vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_270
+	)
+}
+
+/// Swap width and height for transforms that rotate by 90 or 270 degrees.
+fn rotate_extent(extent: vk::Extent2D, transform: vk::SurfaceTransformFlagsKHR) -> vk::Extent2D {
+	match is_rotated_90(transform) {
+		true => vk::Extent2D { width: extent.height, height: extent.width },
+		false => extent,
+	}
+}
+
+/// The surface's current extent if it has one. Otherwise `window_extent`, rotated into the surface's native orientation.
+fn swapchain_image_extent(surface_capabilities: &vk::SurfaceCapabilitiesKHR, window_extent: vk::Extent2D) -> vk::Extent2D {
+	match surface_capabilities.current_extent {
+		NO_CURRENT_EXTENT => rotate_extent(window_extent, select_pre_transform(surface_capabilities)),
+		current => current,
+	}
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct SwapchainConfig {
+	surface_format: vk::SurfaceFormatKHR,
+	present_mode: vk::PresentModeKHR,
+	extent: vk::Extent2D,
+	num_images: u32,
+	pre_transform: vk::SurfaceTransformFlagsKHR,
+	composite_alpha: vk::CompositeAlphaFlagsKHR,
+}
+
+
+enum AcquireResult {
+	Acquired {
+		image: SwapchainImage,
+		suboptimal: bool,
+	},
+
+	OutOfDate,
+	SurfaceLost,
 }
 
 
@@ -344,16 +895,27 @@ struct Swapchain {
 	vk_swapchain: vk::SwapchainKHR,
 	vk_images: Vec<vk::Image>,
 	vk_image_views: Vec<vk::ImageView>,
+
+	// Signalled by the submit rendering to an image and waited on by its present.
+	// One per image rather than per frame slot, since it can only be reused once the image is acquired again.
+	present_semaphores: Vec<vk::Semaphore>,
+
+	// Signalled once the presentation engine is done with the present semaphore and anything else used by the last present of each image.
+	// Only available with swapchain_maintenance1 - otherwise, when presentation is done has to be guessed.
+	present_fences: Option<Vec<vk::Fence>>,
+
+	// Present ids are per swapchain, and only used with present_wait.
+	last_present_id: u64,
+	last_waited_present_id: u64,
+	last_waited_present_time: Option<(u64, Instant)>,
+
+	usage_tracker: gfx::UsageTracker,
 }
 
 impl Swapchain {
-	fn new(core: &gfx::Core, surface: vk::SurfaceKHR, format: vk::Format, present_mode: vk::PresentModeKHR, extent: vk::Extent2D, num_images: u32, old_swapchain: Option<&Swapchain>) -> anyhow::Result<Swapchain> {
-		let format_srgb = match format {
-			vk::Format::R8G8B8A8_UNORM => vk::Format::R8G8B8A8_SRGB,
-			vk::Format::B8G8R8A8_UNORM => vk::Format::B8G8R8A8_SRGB,
-			vk::Format::A8B8G8R8_UNORM_PACK32 => vk::Format::A8B8G8R8_SRGB_PACK32,
-			x => x,
-		};
+	fn new(core: &gfx::Core, surface: vk::SurfaceKHR, config: &SwapchainConfig, old_swapchain: Option<&Swapchain>) -> anyhow::Result<Swapchain> {
+		let format = config.surface_format.format;
+		let format_srgb = srgb_view_format(format);
 
 		let formats = [format, format_srgb];
 		let mut format_list_info = vk::ImageFormatListCreateInfo::default()
@@ -361,16 +923,16 @@ impl Swapchain {
 
 		let mut swapchain_info = vk::SwapchainCreateInfoKHR::default()
 			.surface(surface)
-			.min_image_count(num_images)
+			.min_image_count(config.num_images)
 			.image_format(format)
-			.image_color_space(vk::ColorSpaceKHR::SRGB_NONLINEAR)
-			.image_extent(extent)
+			.image_color_space(config.surface_format.color_space)
+			.image_extent(config.extent)
 			.image_array_layers(1)
 			.image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
 			.image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-			.pre_transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
-			.composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-			.present_mode(present_mode)
+			.pre_transform(config.pre_transform)
+			.composite_alpha(config.composite_alpha)
+			.present_mode(config.present_mode)
 			.clipped(true);
 
 		if let Some(old_swapchain) = old_swapchain {
@@ -383,7 +945,13 @@ impl Swapchain {
 				.push_next(&mut format_list_info);
 		}
 
-		let vk_swapchain = unsafe { core.swapchain_fns.create_swapchain(&swapchain_info, None).context("Creating swapchain")? };
+		let mut present_scaling_info = select_present_scaling(core, surface, config.present_mode)?;
+		if let Some(present_scaling_info) = present_scaling_info.as_mut() {
+			swapchain_info = swapchain_info.push_next(present_scaling_info);
+		}
+
+		let vk_swapchain = unsafe { core.swapchain_fns.create_swapchain(&swapchain_info, core.allocation_callbacks()).context("Creating swapchain")? };
+		core.register_object(vk_swapchain, "Swapchain");
 		let vk_images = unsafe { core.swapchain_fns.get_swapchain_images(vk_swapchain).context("Getting swapchain images")? };
 
 		let vk_image_views: Vec<_> = vk_images.iter()
@@ -409,54 +977,146 @@ impl Swapchain {
 							.layer_count(1)
 					);
 
-				core.vk_device.create_image_view(&create_info, None).context("Creating swapchain image views")
+				core.vk_device.create_image_view(&create_info, core.allocation_callbacks()).context("Creating swapchain image views")
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		for &image_view in vk_image_views.iter() {
+			core.register_object(image_view, "Swapchain Image View");
+		}
+
+		let present_semaphores = vk_images.iter()
+			.map(|_| unsafe {
+				core.vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), core.allocation_callbacks())
+					.context("Creating swapchain present semaphores")
 			})
-			.collect::<Result<_, _>>()?;
+			.collect::<anyhow::Result<Vec<_>>>()?;
+
+		for &semaphore in present_semaphores.iter() {
+			core.register_object(semaphore, "Present Semaphore");
+		}
+
+		let present_fences = if core.supports_swapchain_maintenance1 {
+			// Start signalled, so that images that are never presented don't hold up deletion.
+			let present_fences = vk_images.iter()
+				.map(|_| unsafe {
+					core.vk_device.create_fence(&vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED), core.allocation_callbacks())
+						.context("Creating swapchain present fences")
+				})
+				.collect::<anyhow::Result<Vec<_>>>()?;
+
+			for &fence in present_fences.iter() {
+				core.register_object(fence, "Present Fence");
+			}
+
+			Some(present_fences)
+		} else {
+			None
+		};
 
 		Ok(Swapchain {
 			vk_swapchain,
 			vk_images,
 			vk_image_views,
+			present_semaphores,
+			present_fences,
+
+			last_present_id: 0,
+			last_waited_present_id: 0,
+			last_waited_present_time: None,
+
+			usage_tracker: gfx::UsageTracker::new(),
 		})
 	}
 
-	fn queue_deletion(&self, deletion_queue: &mut gfx::DeletionQueue, timeline_value: u64) {
-		deletion_queue.queue_deletion_after(self.vk_swapchain, timeline_value);
+	fn queue_deletion(&self, deletion_queue: &mut gfx::DeletionQueue) {
+		let Some(present_fences) = &self.present_fences else {
+			// Views must go before the swapchain that owns their images.
+			for image_view in self.vk_image_views.iter() {
+				deletion_queue.queue_deletion_after_use(*image_view, &self.usage_tracker);
+			}
+
+			for semaphore in self.present_semaphores.iter() {
+				deletion_queue.queue_deletion_after_use(*semaphore, &self.usage_tracker);
+			}
+
+			deletion_queue.queue_deletion_after_use(self.vk_swapchain, &self.usage_tracker);
+			return
+		};
+
+		// With present fences we know exactly when presentation is done with everything.
+		let timeline_value = self.usage_tracker.last_used_timeline_value();
 
 		for image_view in self.vk_image_views.iter() {
-			deletion_queue.queue_deletion_after(*image_view, timeline_value);
+			deletion_queue.queue_deletion_after_fences(*image_view, timeline_value, present_fences);
+		}
+
+		for semaphore in self.present_semaphores.iter() {
+			deletion_queue.queue_deletion_after_fences(*semaphore, timeline_value, present_fences);
+		}
+
+		deletion_queue.queue_deletion_after_fences(self.vk_swapchain, timeline_value, present_fences);
+
+		// Fences last, since they're what everything else is waiting on.
+		for fence in present_fences.iter() {
+			deletion_queue.queue_deletion_after_fences(*fence, timeline_value, present_fences);
 		}
 	}
 
-	fn acquire_image(&self, core: &gfx::Core, image_acquire: vk::Semaphore, timeout_ns: u64) -> anyhow::Result<SwapchainImage> {
-		let (image_index, _) = unsafe {
+	fn set_hdr_metadata(&self, core: &gfx::Core, metadata: Option<&vk::HdrMetadataEXT<'static>>) {
+		let Some(metadata) = metadata else {
+			return
+		};
+
+		let Some(hdr_metadata_fns) = &core.hdr_metadata_fns else {
+			return
+		};
+
+		unsafe {
+			hdr_metadata_fns.set_hdr_metadata(&[self.vk_swapchain], std::slice::from_ref(metadata));
+		}
+	}
+
+	/// Give acquired but unpresented images back to the presentation engine. Does nothing without swapchain_maintenance1.
+	fn release_images(&self, core: &gfx::Core, image_indices: &[u32]) -> anyhow::Result<()> {
+		let Some(swapchain_maintenance1_fns) = &core.swapchain_maintenance1_fns else {
+			return Ok(());
+		};
+
+		unsafe {
+			swapchain_maintenance1_fns.release_swapchain_images(
+				&vk::ReleaseSwapchainImagesInfoEXT::default()
+					.swapchain(self.vk_swapchain)
+					.image_indices(image_indices)
+			).context("Releasing swapchain images")
+		}
+	}
+
+	fn acquire_image(&self, core: &gfx::Core, image_acquire: vk::Semaphore, timeout_ns: u64) -> anyhow::Result<AcquireResult> {
+		let result = unsafe {
 			core.swapchain_fns.acquire_next_image(
 				self.vk_swapchain,
 				timeout_ns,
 				image_acquire,
 				vk::Fence::null()
-			).context("Acquiring swapchain image")?
+			)
 		};
 
-		Ok(SwapchainImage {
+		let (image_index, suboptimal) = match result {
+			Ok(result) => result,
+			Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => return Ok(AcquireResult::OutOfDate),
+			Err(vk::Result::ERROR_SURFACE_LOST_KHR) => return Ok(AcquireResult::SurfaceLost),
+			Err(error) => return Err(error).context("Acquiring swapchain image"),
+		};
+
+		let image = SwapchainImage {
 			vk_image: self.vk_images[image_index as usize],
 			vk_image_view: self.vk_image_views[image_index as usize],
+			present_semaphore: self.present_semaphores[image_index as usize],
 			image_index,
-		})
-	}
-
-	fn submit_image(&self, core: &gfx::Core, image: SwapchainImage, raster_finish: vk::Semaphore) -> anyhow::Result<()> {
-		unsafe {
-			core.swapchain_fns.queue_present(
-				core.vk_queue,
-				&vk::PresentInfoKHR::default()
-					.swapchains(&[self.vk_swapchain])
-					.image_indices(&[image.image_index])
-					.wait_semaphores(&[raster_finish])
-			).context("Presenting to swapchain")?;
-		}
+		};
 
-		Ok(())
+		Ok(AcquireResult::Acquired{ image, suboptimal })
 	}
 }
 
@@ -464,5 +1124,6 @@ impl Swapchain {
 struct SwapchainImage {
 	vk_image: vk::Image,
 	vk_image_view: vk::ImageView,
+	present_semaphore: vk::Semaphore,
 	image_index: u32,
 }
\ No newline at end of file
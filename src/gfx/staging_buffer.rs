@@ -0,0 +1,647 @@
+use crate::gfx;
+use ash::vk;
+
+use anyhow::Context;
+
+use std::collections::VecDeque;
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct StagingBufferConfig {
+	/// Size of each chunk. Writes larger than this get a chunk to themselves.
+	pub chunk_size: usize,
+
+	/// Once chunks add up to this size, writes will wait for the GPU to catch up rather than allocating more chunks.
+	pub max_size: usize,
+
+	/// How many frames an extra chunk can go without being written to before it is released.
+	/// The first chunk is never released.
+	pub idle_frames_before_release: u32,
+}
+
+impl Default for StagingBufferConfig {
+	fn default() -> Self {
+		StagingBufferConfig {
+			chunk_size: 16 << 20,
+			max_size: 256 << 20,
+			idle_frames_before_release: 120,
+		}
+	}
+}
+
+
+/// A range of a chunk that was written to before a submit, and can't be reused until that submit completes.
+#[derive(Debug)]
+struct InFlightRegion {
+	start: usize,
+	timeline_value: u64,
+}
+
+/// Ring buffer bookkeeping for a [`StagingChunk`], kept apart from the Vulkan objects so that it can be tested on its own.
+#[derive(Debug)]
+struct StagingRing {
+	size: usize,
+
+	/// Address the ring starts at, so that offsets are aligned in terms of the actual mapped address.
+	base_address: usize,
+	write_cursor: usize,
+
+	/// Start of everything written since the last call to `mark_submitted`.
+	pending_region_start: Option<usize>,
+
+	/// Oldest first.
+	in_flight_regions: VecDeque<InFlightRegion>,
+
+	last_write_frame: u64,
+}
+
+impl StagingRing {
+	fn new(size: usize, base_address: usize) -> StagingRing {
+		StagingRing {
+			size,
+			base_address,
+			write_cursor: 0,
+
+			pending_region_start: None,
+			in_flight_regions: VecDeque::new(),
+
+			last_write_frame: 0,
+		}
+	}
+
+	/// Returns whether anything was written since the last submit.
+	fn mark_submitted(&mut self, timeline_value: u64) -> bool {
+		let Some(start) = self.pending_region_start.take() else {
+			return false
+		};
+
+		self.in_flight_regions.push_back(InFlightRegion {
+			start,
+			timeline_value,
+		});
+
+		true
+	}
+
+	fn is_idle(&self) -> bool {
+		self.in_flight_regions.is_empty() && self.pending_region_start.is_none()
+	}
+
+	/// Whether the ring has gone unwritten for long enough that its chunk can be released.
+	fn is_releasable(&self, frame: u64, idle_frames_before_release: u64) -> bool {
+		self.is_idle() && self.last_write_frame + idle_frames_before_release <= frame
+	}
+
+	/// Release any regions whose submits have completed.
+	fn reclaim(&mut self, completed_timeline_value: u64) {
+		while let Some(region) = self.in_flight_regions.front() {
+			if region.timeline_value > completed_timeline_value {
+				break;
+			}
+
+			self.in_flight_regions.pop_front();
+		}
+
+		// Nothing in use, so start from the beginning again to avoid wrapping.
+		if self.is_idle() {
+			self.write_cursor = 0;
+		}
+	}
+
+	fn tail(&self) -> Option<usize> {
+		self.in_flight_regions.front()
+			.map(|region| region.start)
+			.or(self.pending_region_start)
+	}
+
+	fn used_bytes(&self) -> usize {
+		match self.tail() {
+			None => 0,
+			Some(tail) if self.write_cursor >= tail => self.write_cursor - tail,
+			Some(tail) => self.size - tail + self.write_cursor,
+		}
+	}
+
+	fn aligned_offset(&self, offset: usize, alignment: usize) -> usize {
+		(self.base_address + offset).next_multiple_of(alignment) - self.base_address
+	}
+
+	fn try_allocate(&self, size: usize, alignment: usize) -> Option<usize> {
+		let Some(tail) = self.tail() else {
+			return (size <= self.size).then_some(0);
+		};
+
+		let offset = self.aligned_offset(self.write_cursor, alignment);
+
+		// Used space is contiguous from tail to write_cursor - try the end of the ring, and then the start.
+		// Wrapping must leave a gap before tail, otherwise a full ring would look empty.
+		if self.write_cursor >= tail {
+			if offset + size <= self.size {
+				Some(offset)
+			} else if size < tail {
+				Some(0)
+			} else {
+				None
+			}
+
+		// Used space wraps around the end of the ring, so the only free space is between write_cursor and tail.
+		} else {
+			(offset + size < tail).then_some(offset)
+		}
+	}
+
+	fn commit_allocation(&mut self, offset: usize, size: usize, frame: u64) {
+		if self.pending_region_start.is_none() {
+			self.pending_region_start = Some(offset);
+		}
+
+		self.write_cursor = offset + size;
+		self.last_write_frame = frame;
+	}
+}
+
+/// Index of the first ring with room for the write, and the offset to write at.
+/// Earlier rings are always preferred, so that later ones go idle and can be released once the extra space isn't needed.
+fn find_write_space<'a>(rings: impl IntoIterator<Item=&'a StagingRing>, size: usize, alignment: usize) -> Option<(usize, usize)> {
+	rings.into_iter()
+		.enumerate()
+		.find_map(|(ring_index, ring)| {
+			ring.try_allocate(size, alignment)
+				.map(|offset| (ring_index, offset))
+		})
+}
+
+
+/// A persistently mapped ring buffer with its own device address range.
+#[derive(Debug)]
+struct StagingChunk {
+	vk_memory: vk::DeviceMemory,
+	vk_buffer: vk::Buffer,
+
+	mapped_ptr: *mut u8,
+	ring: StagingRing,
+
+	device_address: vk::DeviceAddress,
+
+	last_upload_timeline_value: u64,
+}
+
+impl StagingChunk {
+	fn new(core: &gfx::Core, allocator: &gfx::DeviceAllocator, size: usize) -> anyhow::Result<StagingChunk> {
+		let buffer_usage = vk::BufferUsageFlags::TRANSFER_SRC
+			| vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+
+		let buffer_info = vk::BufferCreateInfo::default()
+			.size(size as u64)
+			.usage(buffer_usage);
+
+		let vk_buffer = unsafe { core.vk_device.create_buffer(&buffer_info, core.allocation_callbacks())? };
+		let buffer_requirements = unsafe { core.vk_device.get_buffer_memory_requirements(vk_buffer) };
+
+		log::info!("Staging chunk memory requirements: size {}MiB - align {}", buffer_requirements.size >> 20, buffer_requirements.alignment);
+
+		let vk_memory = match allocator.allocate_staging_memory(core, buffer_requirements.size) {
+			Ok(vk_memory) => vk_memory,
+			Err(error) => {
+				unsafe { core.vk_device.destroy_buffer(vk_buffer, core.allocation_callbacks()); }
+				return Err(error);
+			}
+		};
+
+		allocator.set_allocation_name(vk_memory, "Staging Buffer Chunk");
+		core.register_object(vk_buffer, "Staging Buffer Chunk");
+
+		// vulkan guarantees that vk_memory will be adequately aligned for anything we want to put in it.
+		// its only at non-zero offsets that we need to care about alignment.
+		unsafe {
+			core.vk_device.bind_buffer_memory(vk_buffer, vk_memory, 0)?;
+		}
+
+		let mapped_ptr = unsafe {
+			let offset = 0;
+			let memory_map_flags = vk::MemoryMapFlags::empty();
+			core.vk_device.map_memory(vk_memory, offset, vk::WHOLE_SIZE, memory_map_flags)?.cast()
+		};
+
+		let device_address = unsafe {
+			core.vk_device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(vk_buffer))
+		};
+
+		// TODO(pat.m): must align mapped_ptr range to VkPhysicalDeviceLimits::nonCoherentAtomSize if memory isn't HOST_COHERENT.
+
+		Ok(StagingChunk {
+			vk_memory,
+			vk_buffer,
+
+			mapped_ptr,
+			ring: StagingRing::new(size, mapped_ptr as usize),
+
+			device_address,
+
+			last_upload_timeline_value: 0,
+		})
+	}
+
+	fn queue_deletion(&self, deletion_queue: &mut gfx::DeletionQueue) {
+		// No unmap required! vkFreeMemory will implicitly unmap memory.
+
+		deletion_queue.queue_deletion_after(self.vk_buffer, self.last_upload_timeline_value);
+		deletion_queue.queue_deletion_after(self.vk_memory, self.last_upload_timeline_value);
+	}
+
+	fn mark_submitted(&mut self, timeline_value: u64) {
+		if self.ring.mark_submitted(timeline_value) {
+			self.last_upload_timeline_value = timeline_value;
+		}
+	}
+}
+
+
+/// Indices of rings that have been idle long enough to be released, in ascending order. The first ring is never released.
+fn releasable_rings<'a>(rings: impl IntoIterator<Item=&'a StagingRing>, frame: u64, idle_frames_before_release: u64) -> Vec<usize> {
+	rings.into_iter()
+		.enumerate()
+		.skip(1)
+		.filter(|(_, ring)| ring.is_releasable(frame, idle_frames_before_release))
+		.map(|(ring_index, _)| ring_index)
+		.collect()
+}
+
+
+/// Space returned by [`StagingBuffer::allocate_write_space`].
+#[derive(Debug, Clone, Copy)]
+pub struct StagingWriteSpace {
+	pub ptr: *mut u8,
+	pub vk_buffer: vk::Buffer,
+	pub buffer_offset: usize,
+	pub device_address: vk::DeviceAddress,
+}
+
+
+/// Chain of ring buffers for data written by the host every frame.
+/// Grows by adding chunks when all existing chunks are in use by the GPU, and releases extra chunks once they've gone unused for a while.
+///
+/// Regions are reclaimed by the timeline value of the submit that used them rather than by [`gfx::Frame::slot_index`].
+/// Every frame slot's submit signals the timeline, so this frees a region no later than the slot coming around again would,
+/// and also covers writes consumed by submits that aren't frames, like transfer queue uploads.
+#[derive(Debug)]
+pub struct StagingBuffer {
+	chunks: Vec<StagingChunk>,
+	config: StagingBufferConfig,
+
+	frame: u64,
+
+	high_water_mark: usize,
+	peak_chunk_count: usize,
+}
+
+impl StagingBuffer {
+	pub fn new(core: &gfx::Core, allocator: &gfx::DeviceAllocator, config: StagingBufferConfig) -> anyhow::Result<StagingBuffer> {
+		let first_chunk = StagingChunk::new(core, allocator, config.chunk_size)?;
+
+		Ok(StagingBuffer {
+			chunks: vec![first_chunk],
+			config,
+
+			frame: 0,
+
+			high_water_mark: 0,
+			peak_chunk_count: 1,
+		})
+	}
+
+	pub fn queue_deletion(&self, deletion_queue: &mut gfx::DeletionQueue) {
+		log::info!("Staging buffer high-water mark: {}KiB (peak of {} chunks)", self.high_water_mark >> 10, self.peak_chunk_count);
+
+		for chunk in self.chunks.iter() {
+			chunk.queue_deletion(deletion_queue);
+		}
+	}
+
+	/// Most bytes that have been in use by the GPU or waiting to be submitted at once.
+	#[allow(dead_code)]
+	pub fn high_water_mark(&self) -> usize {
+		self.high_water_mark
+	}
+
+	pub fn total_size(&self) -> usize {
+		self.chunks.iter().map(|chunk| chunk.ring.size).sum()
+	}
+
+	/// Tag everything written since the last call with the timeline value of the submit that uses it,
+	/// so that it can be reclaimed once that submit completes.
+	pub fn mark_submitted(&mut self, timeline_value: u64) {
+		for chunk in self.chunks.iter_mut() {
+			chunk.mark_submitted(timeline_value);
+		}
+
+		self.frame += 1;
+	}
+
+	/// Reclaim space used by completed submits, and release extra chunks that haven't been written to for `idle_frames_before_release` frames.
+	/// Should be called at the start of every frame before anything is written, so that writes go to the first chunk whenever it has room.
+	pub fn release_idle_chunks(&mut self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue) -> anyhow::Result<()> {
+		let completed_timeline_value = unsafe {
+			core.vk_device.get_semaphore_counter_value(core.vk_timeline_semaphore)?
+		};
+
+		for chunk in self.chunks.iter_mut() {
+			chunk.ring.reclaim(completed_timeline_value);
+		}
+
+		let idle_frames_before_release = self.config.idle_frames_before_release as u64;
+		let releasable = releasable_rings(self.chunks.iter().map(|chunk| &chunk.ring), self.frame, idle_frames_before_release);
+
+		// Highest index first, so that removing one doesn't shift the others. Order is kept since earlier chunks are preferred.
+		for chunk_index in releasable.into_iter().rev() {
+			let chunk = self.chunks.remove(chunk_index);
+			log::info!("Releasing idle staging chunk ({}MiB)", chunk.ring.size >> 20);
+			chunk.queue_deletion(deletion_queue);
+		}
+
+		Ok(())
+	}
+
+	fn try_allocate(&self, size: usize, alignment: usize) -> Option<(usize, usize)> {
+		find_write_space(self.chunks.iter().map(|chunk| &chunk.ring), size, alignment)
+	}
+
+	pub fn allocate_write_space(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, size: usize, alignment: usize) -> anyhow::Result<StagingWriteSpace> {
+		let (chunk_index, offset) = loop {
+			if let Some(found) = self.try_allocate(size, alignment) {
+				break found;
+			}
+
+			let completed_timeline_value = unsafe {
+				core.vk_device.get_semaphore_counter_value(core.vk_timeline_semaphore)?
+			};
+
+			let num_in_flight: usize = self.chunks.iter().map(|chunk| chunk.ring.in_flight_regions.len()).sum();
+
+			for chunk in self.chunks.iter_mut() {
+				chunk.ring.reclaim(completed_timeline_value);
+			}
+
+			let num_still_in_flight: usize = self.chunks.iter().map(|chunk| chunk.ring.in_flight_regions.len()).sum();
+			if num_still_in_flight < num_in_flight {
+				continue;
+			}
+
+			// Nothing was reclaimed, so grow if we're allowed to.
+			let chunk_size = self.config.chunk_size.max(size + alignment);
+			if self.total_size() + chunk_size <= self.config.max_size || num_still_in_flight == 0 {
+				log::info!("Staging buffer full - adding {}MiB chunk", chunk_size >> 20);
+
+				let chunk = StagingChunk::new(core, allocator, chunk_size)?;
+				self.chunks.push(chunk);
+				self.peak_chunk_count = self.peak_chunk_count.max(self.chunks.len());
+				continue;
+			}
+
+			let oldest_timeline_value = self.chunks.iter()
+				.filter_map(|chunk| chunk.ring.in_flight_regions.front())
+				.map(|region| region.timeline_value)
+				.min()
+				.unwrap();
+
+			log::warn!("Staging buffer full and at max size - waiting for timeline value {oldest_timeline_value}");
+
+			let timeout_ns = 1000*1000*1000;
+
+			unsafe {
+				core.vk_device.wait_semaphores(
+					&vk::SemaphoreWaitInfo::default()
+						.semaphores(&[core.vk_timeline_semaphore])
+						.values(&[oldest_timeline_value]),
+					timeout_ns
+				).context("Waiting for staging buffer space")?;
+			}
+		};
+
+		let chunk = &mut self.chunks[chunk_index];
+		chunk.ring.commit_allocation(offset, size, self.frame);
+
+		let write_space = StagingWriteSpace {
+			ptr: unsafe { chunk.mapped_ptr.add(offset) },
+			vk_buffer: chunk.vk_buffer,
+			buffer_offset: offset,
+			device_address: chunk.device_address + offset as u64,
+		};
+
+		let used_bytes = self.chunks.iter().map(|chunk| chunk.ring.used_bytes()).sum();
+		self.high_water_mark = self.high_water_mark.max(used_bytes);
+
+		Ok(write_space)
+	}
+
+	/// Copy `data` into the staging buffer, returning its device address.
+	pub fn write<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, data: &T) -> anyhow::Result<gfx::GpuPtr<T>>
+		where T: bytemuck::NoUninit
+	{
+		self.write_aligned(core, allocator, data, default_alignment::<T>())
+	}
+
+	/// Same as [`StagingBuffer::write`], but with an explicit alignment. Will never be less aligned than T requires.
+	pub fn write_aligned<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, data: &T, alignment: usize) -> anyhow::Result<gfx::GpuPtr<T>>
+		where T: bytemuck::NoUninit
+	{
+		self.write_slice_aligned(core, allocator, std::slice::from_ref(data), alignment)
+	}
+
+	/// Copy `data` into the staging buffer contiguously, returning the device address of the first element.
+	#[allow(dead_code)]
+	pub fn write_slice<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, data: &[T]) -> anyhow::Result<gfx::GpuPtr<T>>
+		where T: bytemuck::NoUninit
+	{
+		self.write_slice_aligned(core, allocator, data, default_alignment::<T>())
+	}
+
+	pub fn write_slice_aligned<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, data: &[T], alignment: usize) -> anyhow::Result<gfx::GpuPtr<T>>
+		where T: bytemuck::NoUninit
+	{
+		let bytes: &[u8] = bytemuck::cast_slice(data);
+		let alignment = alignment.max(std::mem::align_of::<T>());
+		let write_space = self.allocate_write_space(core, allocator, bytes.len(), alignment)?;
+
+		unsafe {
+			std::ptr::copy_nonoverlapping(bytes.as_ptr(), write_space.ptr, bytes.len());
+		}
+
+		Ok(gfx::GpuPtr::from_address(write_space.device_address))
+	}
+
+	/// Reserve space for `len` elements and hand it out to be written in place, avoiding building the data on the host first.
+	/// The contents of the returned slice are whatever was last written to that part of the staging buffer.
+	#[allow(dead_code)]
+	pub fn allocate_slice<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, len: usize, alignment: usize) -> anyhow::Result<StagingSlice<'_, T>>
+		where T: bytemuck::Pod
+	{
+		let alignment = alignment.max(std::mem::align_of::<T>());
+		let size = len.checked_mul(std::mem::size_of::<T>()).context("Staging slice size overflows")?;
+		let write_space = self.allocate_write_space(core, allocator, size, alignment)?;
+
+		// Pod means any bit pattern is a valid T, and allocate_write_space guarantees nothing else is using this range.
+		let data = unsafe {
+			std::slice::from_raw_parts_mut(write_space.ptr.cast::<T>(), len)
+		};
+
+		Ok(StagingSlice {
+			data,
+			gpu_ptr: gfx::GpuPtr::from_address(write_space.device_address),
+		})
+	}
+}
+
+
+/// Alignment used for writes that don't specify one. Shaders declare buffer_references with buffer_reference_align = 8.
+pub const DEFAULT_STAGING_ALIGNMENT: usize = 8;
+
+fn default_alignment<T>() -> usize {
+	std::mem::align_of::<T>().max(DEFAULT_STAGING_ALIGNMENT)
+}
+
+
+/// Mapped staging memory returned by [`StagingBuffer::allocate_slice`], to be filled in before the next submit.
+#[derive(Debug)]
+pub struct StagingSlice<'a, T> {
+	data: &'a mut [T],
+	gpu_ptr: gfx::GpuPtr<T>,
+}
+
+impl<T> StagingSlice<'_, T> {
+	/// Device address of the first element.
+	#[allow(dead_code)]
+	pub fn gpu_ptr(&self) -> gfx::GpuPtr<T> {
+		self.gpu_ptr
+	}
+}
+
+impl<T> std::ops::Deref for StagingSlice<'_, T> {
+	type Target = [T];
+
+	fn deref(&self) -> &[T] {
+		self.data
+	}
+}
+
+impl<T> std::ops::DerefMut for StagingSlice<'_, T> {
+	fn deref_mut(&mut self) -> &mut [T] {
+		self.data
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write(ring: &mut StagingRing, size: usize, frame: u64) -> Option<usize> {
+		let offset = ring.try_allocate(size, 1)?;
+		ring.commit_allocation(offset, size, frame);
+		Some(offset)
+	}
+
+	#[test]
+	fn ring_wraps_to_start_once_tail_is_reclaimed() {
+		let mut ring = StagingRing::new(100, 0);
+
+		assert_eq!(write(&mut ring, 40, 0), Some(0));
+		ring.mark_submitted(1);
+		assert_eq!(write(&mut ring, 40, 0), Some(40));
+		ring.mark_submitted(2);
+
+		// Doesn't fit after the cursor, and the start is still in use.
+		assert_eq!(ring.try_allocate(30, 1), None);
+
+		ring.reclaim(1);
+		assert_eq!(write(&mut ring, 30, 0), Some(0));
+		assert_eq!(ring.used_bytes(), 100 - 40 + 30);
+
+		// Filling right up to the tail would make a full ring look empty.
+		assert_eq!(ring.try_allocate(10, 1), None);
+		assert_eq!(write(&mut ring, 9, 0), Some(30));
+	}
+
+	#[test]
+	fn used_bytes_counts_pending_writes_that_wrapped() {
+		let mut ring = StagingRing::new(100, 0);
+
+		assert_eq!(write(&mut ring, 80, 0), Some(0));
+		ring.mark_submitted(1);
+		assert_eq!(write(&mut ring, 10, 0), Some(80));
+		ring.mark_submitted(2);
+		ring.reclaim(1);
+
+		// Pending region starts at 90, and then wraps.
+		assert_eq!(write(&mut ring, 5, 0), Some(90));
+		assert_eq!(write(&mut ring, 15, 0), Some(0));
+		ring.reclaim(2);
+
+		assert_eq!(ring.tail(), Some(90));
+		assert_eq!(ring.used_bytes(), 100 - 90 + 15);
+	}
+
+	#[test]
+	fn idle_ring_restarts_from_zero() {
+		let mut ring = StagingRing::new(100, 0);
+
+		write(&mut ring, 60, 0);
+		ring.mark_submitted(1);
+		ring.reclaim(1);
+
+		assert!(ring.is_idle());
+		assert_eq!(ring.used_bytes(), 0);
+		assert_eq!(write(&mut ring, 60, 0), Some(0));
+	}
+
+	#[test]
+	fn offsets_are_aligned_to_the_mapped_address() {
+		let mut ring = StagingRing::new(100, 4);
+
+		assert_eq!(write(&mut ring, 1, 0), Some(0));
+		assert_eq!(ring.try_allocate(8, 8), Some(4));
+	}
+
+	#[test]
+	fn extra_rings_are_released_once_writes_fit_in_the_first() {
+		let idle_frames_before_release = 10;
+		let frames_in_flight = 2;
+
+		let mut rings = vec![StagingRing::new(100, 0)];
+
+		for frame in 0..100u64 {
+			// Start of frame: everything but the last frames_in_flight submits has completed.
+			let completed_timeline_value = frame.saturating_sub(frames_in_flight);
+			for ring in rings.iter_mut() {
+				ring.reclaim(completed_timeline_value);
+			}
+
+			for ring_index in releasable_rings(&rings, frame, idle_frames_before_release).into_iter().rev() {
+				rings.remove(ring_index);
+			}
+
+			// A burst of large writes for the first few frames forces a second ring, after which writes are small.
+			let size = if frame < 5 { 60 } else { 10 };
+
+			let (ring_index, offset) = match find_write_space(&rings, size, 1) {
+				Some(found) => found,
+				None => {
+					rings.push(StagingRing::new(100, 0));
+					find_write_space(&rings, size, 1).unwrap()
+				}
+			};
+
+			if frame >= 5 {
+				assert_eq!(ring_index, 0, "small write on frame {frame} didn't go to the first ring");
+			}
+
+			rings[ring_index].commit_allocation(offset, size, frame);
+
+			for ring in rings.iter_mut() {
+				ring.mark_submitted(frame + 1);
+			}
+		}
+
+		assert_eq!(rings.len(), 1);
+	}
+}
@@ -0,0 +1,556 @@
+use crate::gfx;
+use ash::vk;
+
+use std::collections::VecDeque;
+
+
+/// Staging offsets for image copies must be a multiple of the texel block size, which is never more than 16 bytes for the formats we care about.
+const IMAGE_UPLOAD_ALIGNMENT: usize = 16;
+const BUFFER_UPLOAD_ALIGNMENT: usize = 4;
+
+
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct ImageUploadInfo {
+	pub vk_image: vk::Image,
+	pub subresource: vk::ImageSubresourceLayers,
+	pub offset: vk::Offset3D,
+	pub extent: vk::Extent3D,
+
+	/// Layout the image is left in once the upload completes.
+	pub final_layout: vk::ImageLayout,
+}
+
+#[derive(Debug)]
+struct BufferUpload {
+	src_buffer: vk::Buffer,
+	dst_buffer: vk::Buffer,
+	region: vk::BufferCopy,
+}
+
+#[derive(Debug)]
+struct ImageUpload {
+	src_buffer: vk::Buffer,
+	dst_image: vk::Image,
+	region: vk::BufferImageCopy,
+	final_layout: vk::ImageLayout,
+}
+
+impl ImageUpload {
+	fn subresource_range(&self) -> vk::ImageSubresourceRange {
+		let subresource = &self.region.image_subresource;
+
+		vk::ImageSubresourceRange::default()
+			.aspect_mask(subresource.aspect_mask)
+			.base_mip_level(subresource.mip_level)
+			.level_count(1)
+			.base_array_layer(subresource.base_array_layer)
+			.layer_count(subresource.layer_count)
+	}
+}
+
+
+/// A point on a timeline that an upload batch will have completed by.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadCompletion {
+	pub vk_semaphore: vk::Semaphore,
+	pub timeline_value: u64,
+}
+
+impl UploadCompletion {
+	#[allow(dead_code)]
+	pub fn is_complete(&self, core: &gfx::Core) -> anyhow::Result<bool> {
+		let current_value = unsafe { core.vk_device.get_semaphore_counter_value(self.vk_semaphore)? };
+		Ok(current_value >= self.timeline_value)
+	}
+
+	#[allow(dead_code)]
+	pub fn wait(&self, core: &gfx::Core, timeout_ns: u64) -> anyhow::Result<()> {
+		unsafe {
+			core.vk_device.wait_semaphores(
+				&vk::SemaphoreWaitInfo::default()
+					.semaphores(&[self.vk_semaphore])
+					.values(&[self.timeline_value]),
+				timeout_ns
+			)?;
+		}
+
+		Ok(())
+	}
+}
+
+
+/// Everything needed to submit uploads to the dedicated transfer queue.
+struct TransferContext {
+	vk_queue: vk::Queue,
+	family_index: u32,
+
+	vk_cmd_pool: vk::CommandPool,
+	free_cmd_buffers: Vec<vk::CommandBuffer>,
+	in_flight_cmd_buffers: VecDeque<(vk::CommandBuffer, u64)>,
+
+	vk_timeline_semaphore: vk::Semaphore,
+	timeline_value: u64,
+}
+
+impl TransferContext {
+	fn new(core: &gfx::Core, transfer_queue: gfx::TransferQueue) -> anyhow::Result<TransferContext> {
+		let vk_cmd_pool = unsafe {
+			let create_info = vk::CommandPoolCreateInfo::default()
+				.queue_family_index(transfer_queue.family_index)
+				.flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER | vk::CommandPoolCreateFlags::TRANSIENT);
+
+			core.vk_device.create_command_pool(&create_info, core.allocation_callbacks())?
+		};
+
+		let vk_timeline_semaphore = unsafe {
+			let mut timeline_create_info = vk::SemaphoreTypeCreateInfo::default()
+				.semaphore_type(vk::SemaphoreType::TIMELINE)
+				.initial_value(0);
+
+			match core.vk_device.create_semaphore(&vk::SemaphoreCreateInfo::default().push_next(&mut timeline_create_info), core.allocation_callbacks()) {
+				Ok(vk_semaphore) => vk_semaphore,
+				Err(error) => {
+					core.vk_device.destroy_command_pool(vk_cmd_pool, core.allocation_callbacks());
+					return Err(error.into());
+				}
+			}
+		};
+
+		core.register_object(vk_cmd_pool, "Transfer Command Pool");
+		core.register_object(vk_timeline_semaphore, "Transfer Timeline Semaphore");
+
+		Ok(TransferContext {
+			vk_queue: transfer_queue.vk_queue,
+			family_index: transfer_queue.family_index,
+
+			vk_cmd_pool,
+			free_cmd_buffers: Vec::new(),
+			in_flight_cmd_buffers: VecDeque::new(),
+
+			vk_timeline_semaphore,
+			timeline_value: 0,
+		})
+	}
+
+	fn acquire_cmd_buffer(&mut self, core: &gfx::Core) -> anyhow::Result<vk::CommandBuffer> {
+		let completed_value = unsafe { core.vk_device.get_semaphore_counter_value(self.vk_timeline_semaphore)? };
+
+		while let Some(&(vk_cmd_buffer, timeline_value)) = self.in_flight_cmd_buffers.front() {
+			if timeline_value > completed_value {
+				break;
+			}
+
+			self.in_flight_cmd_buffers.pop_front();
+			self.free_cmd_buffers.push(vk_cmd_buffer);
+		}
+
+		if let Some(vk_cmd_buffer) = self.free_cmd_buffers.pop() {
+			return Ok(vk_cmd_buffer);
+		}
+
+		let vk_cmd_buffers = unsafe {
+			core.vk_device.allocate_command_buffers(
+				&vk::CommandBufferAllocateInfo::default()
+					.command_pool(self.vk_cmd_pool)
+					.level(vk::CommandBufferLevel::PRIMARY)
+					.command_buffer_count(1)
+			)?
+		};
+
+		Ok(vk_cmd_buffers[0])
+	}
+}
+
+
+/// Records copies from [`gfx::StagingBuffer`] memory into device-local buffers and images.
+///
+/// Uploads are collected until [`Uploader::flush`], which records them all at once along with the barriers needed to use the results.
+/// If a dedicated transfer queue is in use, copies are submitted there, and ownership of the destination is handed over to the graphics queue.
+/// Anything uploaded through the transfer queue must not be in use by the GPU, and image uploads always discard previous contents of the subresource.
+pub struct Uploader {
+	transfer: Option<TransferContext>,
+
+	buffer_uploads: Vec<BufferUpload>,
+	image_uploads: Vec<ImageUpload>,
+
+	/// Destinations that should be marked as used by the frame they are flushed into.
+	used_resources: Vec<gfx::UsageTracker>,
+}
+
+impl Uploader {
+	/// Uses the dedicated transfer queue if `use_transfer_queue` is set and the device has one, otherwise uploads are recorded into the frame.
+	pub fn new(core: &gfx::Core, use_transfer_queue: bool) -> anyhow::Result<Uploader> {
+		let transfer = match core.transfer_queue {
+			Some(transfer_queue) if use_transfer_queue => Some(TransferContext::new(core, transfer_queue)?),
+			_ => None,
+		};
+
+		Ok(Uploader {
+			transfer,
+
+			buffer_uploads: Vec::new(),
+			image_uploads: Vec::new(),
+
+			used_resources: Vec::new(),
+		})
+	}
+
+	pub fn queue_deletion(&self, core: &gfx::Core, deletion_queue: &mut gfx::DeletionQueue) {
+		// Every transfer submit is waited on by a later graphics submit, so the graphics timeline is enough here.
+		// Destroying the pool frees its command buffers.
+		if let Some(transfer) = &self.transfer {
+			deletion_queue.queue_deletion(transfer.vk_cmd_pool, core);
+			deletion_queue.queue_deletion(transfer.vk_timeline_semaphore, core);
+		}
+	}
+
+	#[allow(dead_code)]
+	pub fn uses_transfer_queue(&self) -> bool {
+		self.transfer.is_some()
+	}
+
+	pub fn has_pending_uploads(&self) -> bool {
+		!self.buffer_uploads.is_empty() || !self.image_uploads.is_empty()
+	}
+
+	/// Copy `data` into `vk_buffer` at byte offset `dst_offset`. `vk_buffer` must have been created with TRANSFER_DST usage.
+	pub fn upload_to_buffer<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, staging_buffer: &mut gfx::StagingBuffer,
+		data: &[T], vk_buffer: vk::Buffer, dst_offset: u64) -> anyhow::Result<()>
+		where T: bytemuck::NoUninit
+	{
+		let bytes: &[u8] = bytemuck::cast_slice(data);
+		if bytes.is_empty() {
+			return Ok(());
+		}
+
+		let write_space = write_staging_bytes(core, allocator, staging_buffer, bytes, BUFFER_UPLOAD_ALIGNMENT)?;
+
+		self.buffer_uploads.push(BufferUpload {
+			src_buffer: write_space.vk_buffer,
+			dst_buffer: vk_buffer,
+			region: vk::BufferCopy::default()
+				.src_offset(write_space.buffer_offset as u64)
+				.dst_offset(dst_offset)
+				.size(bytes.len() as u64),
+		});
+
+		Ok(())
+	}
+
+	/// Copy `data` into `buffer`, starting at element `first_element`. `buffer` must have been created with TRANSFER_DST usage.
+	pub fn upload_to_typed_buffer<T>(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, staging_buffer: &mut gfx::StagingBuffer,
+		data: &[T], buffer: &gfx::Buffer<T>, first_element: usize) -> anyhow::Result<()>
+		where T: bytemuck::NoUninit
+	{
+		anyhow::ensure!(first_element + data.len() <= buffer.len(),
+			"Upload of {} elements at {first_element} overruns buffer of length {}", data.len(), buffer.len());
+
+		let dst_offset = (first_element * std::mem::size_of::<T>()) as u64;
+		self.upload_to_buffer(core, allocator, staging_buffer, data, buffer.vk_buffer(), dst_offset)?;
+
+		self.used_resources.push(gfx::TrackedResource::usage_tracker(buffer).clone());
+		Ok(())
+	}
+
+	/// Copy tightly packed texel data into a region of an image. The image must have been created with TRANSFER_DST usage.
+	#[allow(dead_code)]
+	pub fn upload_to_image(&mut self, core: &gfx::Core, allocator: &gfx::DeviceAllocator, staging_buffer: &mut gfx::StagingBuffer,
+		data: &[u8], info: &ImageUploadInfo) -> anyhow::Result<()>
+	{
+		let write_space = write_staging_bytes(core, allocator, staging_buffer, data, IMAGE_UPLOAD_ALIGNMENT)?;
+
+		self.image_uploads.push(ImageUpload {
+			src_buffer: write_space.vk_buffer,
+			dst_image: info.vk_image,
+			region: vk::BufferImageCopy::default()
+				.buffer_offset(write_space.buffer_offset as u64)
+				.image_subresource(info.subresource)
+				.image_offset(info.offset)
+				.image_extent(info.extent),
+			final_layout: info.final_layout,
+		});
+
+		Ok(())
+	}
+
+	/// Record all pending uploads so that their results are visible to everything recorded into `frame` afterwards.
+	/// Must be called before the frame's own commands that use the uploaded resources are recorded.
+	/// Returns None if there was nothing to upload.
+	pub fn flush(&mut self, core: &gfx::Core, frame: &mut gfx::Frame) -> anyhow::Result<Option<UploadCompletion>> {
+		if !self.has_pending_uploads() {
+			return Ok(None);
+		}
+
+		let completion = match self.transfer.as_mut() {
+			Some(transfer) => {
+				let completion = submit_transfer(core, transfer, &self.buffer_uploads, &self.image_uploads)?;
+
+				record_acquire_barriers(core, frame.cmd_buffer(), transfer.family_index, core.queue_family_index, &self.buffer_uploads, &self.image_uploads);
+				frame.wait_for_semaphore(completion.vk_semaphore, completion.timeline_value, vk::PipelineStageFlags2::ALL_COMMANDS);
+
+				completion
+			}
+
+			None => {
+				record_uploads(core, frame.cmd_buffer(), None, &self.buffer_uploads, &self.image_uploads);
+
+				// Completes along with the frame.
+				UploadCompletion {
+					vk_semaphore: core.vk_timeline_semaphore,
+					timeline_value: core.timeline_value.get() + 1,
+				}
+			}
+		};
+
+		log::trace!("Flushed {} buffer and {} image uploads", self.buffer_uploads.len(), self.image_uploads.len());
+
+		self.buffer_uploads.clear();
+		self.image_uploads.clear();
+
+		for usage_tracker in self.used_resources.drain(..) {
+			frame.mark_used(&usage_tracker);
+		}
+
+		Ok(Some(completion))
+	}
+}
+
+
+fn write_staging_bytes(core: &gfx::Core, allocator: &gfx::DeviceAllocator, staging_buffer: &mut gfx::StagingBuffer, bytes: &[u8], alignment: usize)
+	-> anyhow::Result<gfx::StagingWriteSpace>
+{
+	let write_space = staging_buffer.allocate_write_space(core, allocator, bytes.len(), alignment)?;
+
+	unsafe {
+		std::ptr::copy_nonoverlapping(bytes.as_ptr(), write_space.ptr, bytes.len());
+	}
+
+	Ok(write_space)
+}
+
+fn submit_transfer(core: &gfx::Core, transfer: &mut TransferContext, buffer_uploads: &[BufferUpload], image_uploads: &[ImageUpload]) -> anyhow::Result<UploadCompletion> {
+	let vk_cmd_buffer = transfer.acquire_cmd_buffer(core)?;
+
+	unsafe {
+		core.vk_device.begin_command_buffer(vk_cmd_buffer,
+			&vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT))?;
+	}
+
+	let queue_family_transfer = Some((transfer.family_index, core.queue_family_index));
+	record_uploads(core, vk_cmd_buffer, queue_family_transfer, buffer_uploads, image_uploads);
+
+	unsafe {
+		core.vk_device.end_command_buffer(vk_cmd_buffer)?;
+	}
+
+	let timeline_value = transfer.timeline_value + 1;
+
+	unsafe {
+		core.vk_device.queue_submit2(
+			transfer.vk_queue,
+			&[
+				vk::SubmitInfo2::default()
+					.command_buffer_infos(&[
+						vk::CommandBufferSubmitInfo::default()
+							.command_buffer(vk_cmd_buffer)
+					])
+					.signal_semaphore_infos(&[
+						vk::SemaphoreSubmitInfo::default()
+							.semaphore(transfer.vk_timeline_semaphore)
+							.stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+							.value(timeline_value)
+					])
+			],
+			vk::Fence::null()
+		)?;
+	}
+
+	// Only bump the timeline once the submit has succeeded, otherwise later waits would never complete.
+	transfer.timeline_value = timeline_value;
+	transfer.in_flight_cmd_buffers.push_back((vk_cmd_buffer, timeline_value));
+
+	Ok(UploadCompletion {
+		vk_semaphore: transfer.vk_timeline_semaphore,
+		timeline_value,
+	})
+}
+
+/// Record copies and the barriers around them.
+/// If `queue_family_transfer` is Some((src, dst)), destinations are released to the dst queue family afterwards rather than made visible,
+/// and a matching acquire must be recorded with [`record_acquire_barriers`].
+fn record_uploads(core: &gfx::Core, vk_cmd_buffer: vk::CommandBuffer, queue_family_transfer: Option<(u32, u32)>,
+	buffer_uploads: &[BufferUpload], image_uploads: &[ImageUpload])
+{
+	// Host writes to staging memory are made visible by vkQueueSubmit, so only the destinations need barriers.
+
+	let pre_copy_image_barriers: Vec<_> = image_uploads.iter()
+		.map(|upload| {
+			vk::ImageMemoryBarrier2::default()
+				.image(upload.dst_image)
+				.old_layout(vk::ImageLayout::UNDEFINED) // Previous contents are discarded
+				.new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+
+				.src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+				.src_access_mask(vk::AccessFlags2::NONE)
+
+				.dst_stage_mask(vk::PipelineStageFlags2::COPY)
+				.dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+				.subresource_range(upload.subresource_range())
+		})
+		.collect();
+
+	// Anything still reading or writing destination buffers must finish before they are overwritten.
+	let pre_copy_memory_barriers = match buffer_uploads.is_empty() || queue_family_transfer.is_some() {
+		true => Vec::new(),
+		false => vec![
+			vk::MemoryBarrier2::default()
+				.src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+				.src_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+				.dst_stage_mask(vk::PipelineStageFlags2::COPY)
+				.dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+		],
+	};
+
+	unsafe {
+		core.vk_device.cmd_pipeline_barrier2(
+			vk_cmd_buffer,
+			&vk::DependencyInfo::default()
+				.memory_barriers(&pre_copy_memory_barriers)
+				.image_memory_barriers(&pre_copy_image_barriers)
+		);
+
+		for upload in buffer_uploads {
+			core.vk_device.cmd_copy_buffer(vk_cmd_buffer, upload.src_buffer, upload.dst_buffer, &[upload.region]);
+		}
+
+		for upload in image_uploads {
+			core.vk_device.cmd_copy_buffer_to_image(vk_cmd_buffer, upload.src_buffer, upload.dst_image,
+				vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[upload.region]);
+		}
+	}
+
+	match queue_family_transfer {
+		Some((src_family_index, dst_family_index)) => {
+			let (buffer_barriers, image_barriers) = queue_family_transfer_barriers(src_family_index, dst_family_index, buffer_uploads, image_uploads);
+
+			// Release half of the ownership transfer - dst stage and access are ignored.
+			let buffer_barriers: Vec<_> = buffer_barriers.into_iter()
+				.map(|barrier| barrier
+					.src_stage_mask(vk::PipelineStageFlags2::COPY)
+					.src_access_mask(vk::AccessFlags2::TRANSFER_WRITE))
+				.collect();
+
+			let image_barriers: Vec<_> = image_barriers.into_iter()
+				.map(|barrier| barrier
+					.src_stage_mask(vk::PipelineStageFlags2::COPY)
+					.src_access_mask(vk::AccessFlags2::TRANSFER_WRITE))
+				.collect();
+
+			unsafe {
+				core.vk_device.cmd_pipeline_barrier2(
+					vk_cmd_buffer,
+					&vk::DependencyInfo::default()
+						.buffer_memory_barriers(&buffer_barriers)
+						.image_memory_barriers(&image_barriers)
+				);
+			}
+		}
+
+		None => {
+			let post_copy_memory_barriers = match buffer_uploads.is_empty() {
+				true => Vec::new(),
+				false => vec![
+					vk::MemoryBarrier2::default()
+						.src_stage_mask(vk::PipelineStageFlags2::COPY)
+						.src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+						.dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+						.dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+				],
+			};
+
+			let post_copy_image_barriers: Vec<_> = image_uploads.iter()
+				.map(|upload| {
+					vk::ImageMemoryBarrier2::default()
+						.image(upload.dst_image)
+						.old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+						.new_layout(upload.final_layout)
+
+						.src_stage_mask(vk::PipelineStageFlags2::COPY)
+						.src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+
+						.dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+						.dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+						.subresource_range(upload.subresource_range())
+				})
+				.collect();
+
+			unsafe {
+				core.vk_device.cmd_pipeline_barrier2(
+					vk_cmd_buffer,
+					&vk::DependencyInfo::default()
+						.memory_barriers(&post_copy_memory_barriers)
+						.image_memory_barriers(&post_copy_image_barriers)
+				);
+			}
+		}
+	}
+}
+
+/// Acquire half of the ownership transfer recorded by [`record_uploads`] - src stage and access are ignored.
+fn record_acquire_barriers(core: &gfx::Core, vk_cmd_buffer: vk::CommandBuffer, src_family_index: u32, dst_family_index: u32,
+	buffer_uploads: &[BufferUpload], image_uploads: &[ImageUpload])
+{
+	let (buffer_barriers, image_barriers) = queue_family_transfer_barriers(src_family_index, dst_family_index, buffer_uploads, image_uploads);
+
+	let buffer_barriers: Vec<_> = buffer_barriers.into_iter()
+		.map(|barrier| barrier
+			.dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+			.dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE))
+		.collect();
+
+	let image_barriers: Vec<_> = image_barriers.into_iter()
+		.map(|barrier| barrier
+			.dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+			.dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE))
+		.collect();
+
+	unsafe {
+		core.vk_device.cmd_pipeline_barrier2(
+			vk_cmd_buffer,
+			&vk::DependencyInfo::default()
+				.buffer_memory_barriers(&buffer_barriers)
+				.image_memory_barriers(&image_barriers)
+		);
+	}
+}
+
+/// Barriers common to both halves of a queue family ownership transfer. Release and acquire must match exactly, including layouts.
+fn queue_family_transfer_barriers(src_family_index: u32, dst_family_index: u32, buffer_uploads: &[BufferUpload], image_uploads: &[ImageUpload])
+	-> (Vec<vk::BufferMemoryBarrier2<'static>>, Vec<vk::ImageMemoryBarrier2<'static>>)
+{
+	let buffer_barriers = buffer_uploads.iter()
+		.map(|upload| {
+			vk::BufferMemoryBarrier2::default()
+				.buffer(upload.dst_buffer)
+				.offset(upload.region.dst_offset)
+				.size(upload.region.size)
+				.src_queue_family_index(src_family_index)
+				.dst_queue_family_index(dst_family_index)
+		})
+		.collect();
+
+	let image_barriers = image_uploads.iter()
+		.map(|upload| {
+			vk::ImageMemoryBarrier2::default()
+				.image(upload.dst_image)
+				.old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+				.new_layout(upload.final_layout)
+				.src_queue_family_index(src_family_index)
+				.dst_queue_family_index(dst_family_index)
+				.subresource_range(upload.subresource_range())
+		})
+		.collect();
+
+	(buffer_barriers, image_barriers)
+}
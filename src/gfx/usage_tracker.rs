@@ -0,0 +1,38 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+
+/// Shared record of the timeline value of the last submit that referenced a resource.
+/// Clones refer to the same record, so a [`gfx::Frame`](crate::gfx::Frame) can hold on to one and update it when it is submitted.
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker(Rc<Cell<u64>>);
+
+impl UsageTracker {
+	pub fn new() -> UsageTracker {
+		UsageTracker::default()
+	}
+
+	/// 0 if the resource has never been used by a submit.
+	pub fn last_used_timeline_value(&self) -> u64 {
+		self.0.get()
+	}
+
+	/// Record a use by the submit that signals `timeline_value`. Never moves backwards.
+	pub fn mark_used(&self, timeline_value: u64) {
+		self.0.set(self.0.get().max(timeline_value));
+	}
+}
+
+
+/// A resource whose deletion is timed by the last submit that used it, rather than the latest submit.
+/// Every submit that references the resource must mark it as used, e.g., with [`gfx::Frame::mark_used`](crate::gfx::Frame::mark_used).
+/// Resources that are never marked fall back to waiting for the latest submit when deleted.
+pub trait TrackedResource {
+	fn usage_tracker(&self) -> &UsageTracker;
+}
+
+impl TrackedResource for UsageTracker {
+	fn usage_tracker(&self) -> &UsageTracker {
+		self
+	}
+}
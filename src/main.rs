@@ -1,16 +1,21 @@
 use winit::{
 	application::ApplicationHandler,
-	event::{WindowEvent, /*ElementState*/},
+	event::{WindowEvent, ElementState},
+	keyboard::{PhysicalKey, KeyCode},
 	event_loop::{EventLoop, ActiveEventLoop, ControlFlow},
 	window::{Window, WindowId},
 	dpi::{LogicalSize, PhysicalSize},
 };
 
+#[allow(unused_imports)]
 use anyhow::Context;
 
 // use ash::prelude::*;
 use ash::vk;
 
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 mod gfx;
 
 
@@ -62,94 +67,104 @@ fn main() -> anyhow::Result<()> {
 
 
 
+const FRAMES_IN_FLIGHT: usize = 2;
+
+// How many presents can be waiting to reach the display before starting on the next frame.
+// Only enforced with VK_KHR_present_wait - otherwise FRAMES_IN_FLIGHT is the only limit.
+const MAX_PENDING_PRESENTS: u64 = 1;
+
 struct App {
 	gfx_core: gfx::Core,
-	window: Option<Window>,
-	presentable_surface: Option<gfx::PresentableSurface>,
 
-	deletion_queue: gfx::DeletionQueue,
+	// Option so that it can be queued for deletion on exit.
+	frame_context: Option<gfx::FrameContext>,
+
+	windows: HashMap<WindowId, AppWindow>,
+
+	// Surfaces can still be in use by the presentation engine after their window is closed, so closed windows are kept until their surface is destroyed.
+	closed_windows: Vec<(vk::SurfaceKHR, Window)>,
+
+	deletion_queue: gfx::SharedDeletionQueue,
 	allocator: gfx::DeviceAllocator,
 	staging_buffer: gfx::StagingBuffer,
+	uploader: gfx::Uploader,
 
-	vk_pipeline: vk::Pipeline,
+	// Option so that it can be dropped before the deletion queue is flushed on exit.
+	pipeline: Option<gfx::OwnedPipeline>,
 	vk_pipeline_layout: vk::PipelineLayout,
 
-	vk_depth_allocation: vk::DeviceMemory,
-	vk_depth_image: vk::Image,
-	vk_depth_view: vk::ImageView,
+	offsets_buffer: Option<gfx::Buffer<[f32; 4]>>,
 
 	time: f32,
-}
+	vsync: bool,
 
-impl App {
-	fn new(gfx_core: gfx::Core) -> App {
-		let vert_sh = create_shader_module(&gfx_core.vk_device, "shaders/main.vs.spv").unwrap();
-		let frag_sh = create_shader_module(&gfx_core.vk_device, "shaders/main.fs.spv").unwrap();
-
-		let (vk_pipeline, vk_pipeline_layout) = create_graphics_pipeline(&gfx_core, vert_sh, frag_sh).unwrap();
+	// Set with --hdr.
+	hdr: bool,
 
-		unsafe {
-			gfx_core.vk_device.destroy_shader_module(vert_sh, None);
-			gfx_core.vk_device.destroy_shader_module(frag_sh, None);
-		};
+	// Set with --transparent.
+	transparent: bool,
 
-		let allocator = gfx::DeviceAllocator::new(&gfx_core).unwrap();
-		let staging_buffer = gfx::StagingBuffer::new(&gfx_core, &allocator).unwrap();
+	// Set with --max-fps=N.
+	min_frame_interval: Option<Duration>,
+	next_frame_time: Instant,
 
-		App {
-			gfx_core,
-			window: None,
-			presentable_surface: None,
-			deletion_queue: gfx::DeletionQueue::default(),
-			allocator,
-			staging_buffer,
-			vk_pipeline,
-			vk_pipeline_layout,
+	last_present_interval_report: Instant,
+}
 
-			vk_depth_allocation: vk::DeviceMemory::null(),
-			vk_depth_image: vk::Image::null(),
-			vk_depth_view: vk::ImageView::null(),
+struct AppWindow {
+	window: Window,
+	presentable_surface: gfx::PresentableSurface,
 
-			time: 0.0,
-		}
-	}
+	// Created when first drawn, and recreated whenever the swapchain extent changes.
+	depth_attachment: Option<DepthAttachment>,
 
-	fn destroy_depth_attachment(&mut self) {
-		if self.vk_depth_view != vk::ImageView::null() {
-			self.deletion_queue.queue_deletion(self.vk_depth_view, &self.gfx_core);
-			self.deletion_queue.queue_deletion(self.vk_depth_image, &self.gfx_core);
-			self.deletion_queue.queue_deletion(self.vk_depth_allocation, &self.gfx_core);
-		}
+	// All windows that requested a redraw are drawn together in one frame.
+	redraw_requested: bool,
+}
 
-		self.vk_depth_view = vk::ImageView::null();
-		self.vk_depth_image = vk::Image::null();
-		self.vk_depth_allocation = vk::DeviceMemory::null();
+impl AppWindow {
+	// Windows whose surface isn't active stop redrawing until it is again - see the Resized and Occluded handlers.
+	fn wants_redraw(&self) -> bool {
+		self.redraw_requested && self.presentable_surface.state() == gfx::SurfaceState::Active
 	}
+}
 
-	fn recreate_depth_attachment(&mut self, width: u32, height: u32) -> anyhow::Result<()> {
-		self.destroy_depth_attachment();
+struct DepthAttachment {
+	// Fields are dropped in declaration order, so the view is queued for deletion before the image it refers to.
+	view: gfx::OwnedImageView,
+	image: gfx::OwnedImage,
+	memory: gfx::OwnedMemory,
 
+	extent: vk::Extent2D,
+}
+
+impl DepthAttachment {
+	fn new(core: &gfx::Core, allocator: &gfx::DeviceAllocator, deletion_queue: &gfx::SharedDeletionQueue, extent: vk::Extent2D) -> anyhow::Result<DepthAttachment> {
 		let image_create_info = vk::ImageCreateInfo::default()
 			.image_type(vk::ImageType::TYPE_2D)
 			.format(vk::Format::D32_SFLOAT)
 			.samples(vk::SampleCountFlags::TYPE_1)
-			.extent(vk::Extent3D{ width, height, depth: 1 })
+			.extent(vk::Extent3D{ width: extent.width, height: extent.height, depth: 1 })
 			.mip_levels(1)
 			.array_layers(1)
 			.tiling(vk::ImageTiling::OPTIMAL)
-			.usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+			// Depth is cleared on load and discarded on store, so doesn't need to be backed by real memory where that's possible
+			.usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
 			.initial_layout(vk::ImageLayout::UNDEFINED)
 			.sharing_mode(vk::SharingMode::EXCLUSIVE);
 
 		unsafe {
-			self.vk_depth_image = self.gfx_core.vk_device.create_image(&image_create_info, None)?;
-			let requirements = self.gfx_core.vk_device.get_image_memory_requirements(self.vk_depth_image);
+			let image = core.vk_device.create_image(&image_create_info, core.allocation_callbacks())?;
+			core.register_object(image, "Depth Attachment");
+			let image = gfx::OwnedImage::new(image, deletion_queue);
+			let requirements = core.vk_device.get_image_memory_requirements(image.handle());
 
-			self.vk_depth_allocation = self.allocator.allocate_device_memory(&self.gfx_core, requirements.size)?;
-			self.gfx_core.vk_device.bind_image_memory(self.vk_depth_image, self.vk_depth_allocation, 0)?;
+			let memory = allocator.allocate_transient_attachment_memory(core, &requirements)?;
+			let memory = gfx::OwnedMemory::new(memory, deletion_queue);
+			core.vk_device.bind_image_memory(image.handle(), memory.handle(), 0)?;
 
 			let view_create_info = vk::ImageViewCreateInfo::default()
-				.image(self.vk_depth_image)
+				.image(image.handle())
 				.view_type(vk::ImageViewType::TYPE_2D)
 				.format(vk::Format::D32_SFLOAT)
 				.components(
@@ -170,209 +185,622 @@ impl App {
 						.layer_count(1)
 				);
 
-			self.vk_depth_view = self.gfx_core.vk_device.create_image_view(&view_create_info, None)?;
-		}
+			let view = core.vk_device.create_image_view(&view_create_info, core.allocation_callbacks())?;
+			core.register_object(view, "Depth Attachment View");
+			let view = gfx::OwnedImageView::new(view, deletion_queue);
 
-		Ok(())
+			Ok(DepthAttachment { view, image, memory, extent })
+		}
 	}
 }
 
-impl ApplicationHandler for App {
-	fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+impl App {
+	fn new(gfx_core: gfx::Core) -> App {
+		let vert_sh = create_shader_module(&gfx_core, "shaders/main.vs.spv").unwrap();
+		let frag_sh = create_shader_module(&gfx_core, "shaders/main.fs.spv").unwrap();
+
+		let (vk_pipeline, vk_pipeline_layout) = create_graphics_pipeline(&gfx_core, vert_sh, frag_sh).unwrap();
+
+		unsafe {
+			gfx_core.vk_device.destroy_shader_module(vert_sh, gfx_core.allocation_callbacks());
+			gfx_core.vk_device.destroy_shader_module(frag_sh, gfx_core.allocation_callbacks());
+		};
+
+		gfx_core.register_object(vk_pipeline, "Main Pipeline");
+		gfx_core.register_object(vk_pipeline_layout, "Main Pipeline Layout");
+
+		let deletion_queue = gfx::SharedDeletionQueue::new(&gfx_core);
+		let pipeline = gfx::OwnedPipeline::new(vk_pipeline, &deletion_queue);
+
+		let allocator = gfx::DeviceAllocator::new(&gfx_core).unwrap();
+		let mut staging_buffer = gfx::StagingBuffer::new(&gfx_core, &allocator, gfx::StagingBufferConfig::default()).unwrap();
+		let mut uploader = gfx::Uploader::new(&gfx_core, true).unwrap();
+
+		let offsets = [
+			[0.0f32, 0.0, 0.0, 0.0],
+			[1.0, 0.0, 1.0, 3.0],
+			[-1.0, 0.0, 3.0, 6.0],
+			[-0.5, 1.0, 2.0, 9.0],
+		];
+
+		let offsets_buffer = gfx::Buffer::new(&gfx_core, &allocator, offsets.len(), vk::BufferUsageFlags::TRANSFER_DST, gfx::MemoryLocation::DeviceLocal).unwrap();
+		uploader.upload_to_typed_buffer(&gfx_core, &allocator, &mut staging_buffer, &offsets, &offsets_buffer, 0).unwrap();
+
+		let frame_context = gfx::FrameContext::new(&gfx_core, FRAMES_IN_FLIGHT).unwrap();
+
+		App {
+			gfx_core,
+			frame_context: Some(frame_context),
+
+			windows: HashMap::new(),
+			closed_windows: Vec::new(),
+
+			deletion_queue,
+			allocator,
+			staging_buffer,
+			uploader,
+			pipeline: Some(pipeline),
+			vk_pipeline_layout,
+
+			offsets_buffer: Some(offsets_buffer),
+
+			time: 0.0,
+			vsync: true,
+			hdr: std::env::args().any(|arg| arg == "--hdr"),
+			transparent: std::env::args().any(|arg| arg == "--transparent"),
+
+			min_frame_interval: std::env::args()
+				.find_map(|arg| arg.strip_prefix("--max-fps=")?.parse::<f32>().ok())
+				.filter(|&max_fps| max_fps > 0.0)
+				.map(|max_fps| Duration::from_secs_f32(1.0 / max_fps)),
+			next_frame_time: Instant::now(),
+
+			last_present_interval_report: Instant::now(),
+		}
+	}
+
+	fn present_mode_preferences(&self) -> Vec<gfx::PresentModePreference> {
+		match self.vsync {
+			true => vec![gfx::PresentModePreference::Vsync],
+			false => vec![gfx::PresentModePreference::LowLatency, gfx::PresentModePreference::Uncapped],
+		}
+	}
+
+	fn color_space_preferences(&self) -> Vec<gfx::ColorSpacePreference> {
+		match self.hdr {
+			true => vec![gfx::ColorSpacePreference::Hdr10, gfx::ColorSpacePreference::ScRgb, gfx::ColorSpacePreference::DisplayP3],
+			false => vec![gfx::ColorSpacePreference::Srgb],
+		}
+	}
+
+	fn composite_alpha_preferences(&self) -> Vec<gfx::CompositeAlphaPreference> {
+		match self.transparent {
+			true => vec![gfx::CompositeAlphaPreference::PreMultiplied, gfx::CompositeAlphaPreference::PostMultiplied],
+			false => vec![gfx::CompositeAlphaPreference::Opaque],
+		}
+	}
+
+	fn open_window(&mut self, event_loop: &ActiveEventLoop) -> anyhow::Result<()> {
 		let window_attrs = Window::default_attributes()
 			.with_title("Vk Fuck")
-			.with_inner_size(LogicalSize::new(1366, 768));
+			.with_inner_size(LogicalSize::new(1366, 768))
+			.with_transparent(self.transparent);
 
-		let window = event_loop.create_window(window_attrs).unwrap();
-		let presentable_surface = gfx::PresentableSurface::new(&self.gfx_core, &window).unwrap();
+		let window = event_loop.create_window(window_attrs)?;
+		let presentable_surface = gfx::PresentableSurface::new(&self.gfx_core, &window, &self.present_mode_preferences(), FRAMES_IN_FLIGHT, &self.color_space_preferences(), &self.composite_alpha_preferences())?;
+		log::info!("Presenting in {:?}", presentable_surface.color_space());
 
-		self.window = Some(window);
-		self.presentable_surface = Some(presentable_surface);
+		if self.transparent && presentable_surface.composite_alpha() == vk::CompositeAlphaFlagsKHR::OPAQUE {
+			log::warn!("Transparent window requested, but the surface only supports opaque composition");
+		}
+
+		self.windows.insert(window.id(), AppWindow {
+			window,
+			presentable_surface,
+			depth_attachment: None,
+			redraw_requested: false,
+		});
+
+		Ok(())
 	}
 
-	fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
-		match event {
-			WindowEvent::CloseRequested => {
-				event_loop.exit();
-			},
+	/// Replace the surface of any window whose surface has been lost, e.g., because the display it was on went away.
+	fn recreate_lost_surfaces(&mut self) {
+		let lost_window_ids: Vec<_> = self.windows.iter()
+			.filter(|(_, app_window)| app_window.presentable_surface.state() == gfx::SurfaceState::Lost)
+			.map(|(&window_id, _)| window_id)
+			.collect();
+
+		for window_id in lost_window_ids {
+			log::warn!("Surface for {window_id:?} lost - recreating");
+
+			let AppWindow{ window, presentable_surface: lost_surface, depth_attachment, redraw_requested } = self.windows.remove(&window_id).unwrap();
+
+			// A window can only have one swapchain at a time, so the lost one has to be gone before its replacement is created.
+			let mut lost_surface_deletions = gfx::DeletionQueue::new(&self.gfx_core);
+			lost_surface.queue_deletion(&mut lost_surface_deletions);
+
+			let timeout_ns = 1000*1000*1000;
+			let wait_result = unsafe {
+				self.gfx_core.vk_device.wait_semaphores(
+					&vk::SemaphoreWaitInfo::default()
+						.semaphores(&[self.gfx_core.vk_timeline_semaphore])
+						.values(&[self.gfx_core.timeline_value.get()]),
+					timeout_ns
+				)
+			};
 
-			WindowEvent::Resized(PhysicalSize{ width, height }) => {
-				if let Some(presentable_surface) = self.presentable_surface.as_mut() {
-					let result = presentable_surface.resize(&self.gfx_core, &mut self.deletion_queue, vk::Extent2D{width, height});
-					if let Err(error) = result {
-						log::error!("Failed to resize presentable surface: {error}");
-					};
+			match wait_result {
+				Ok(()) => unsafe {
+					lost_surface_deletions.destroy_all_immediate(&self.gfx_core, &self.allocator);
+				}
 
-					self.recreate_depth_attachment(width, height).unwrap();
+				// Still in use, so it can't safely be destroyed - dropping the queue reports what gets leaked.
+				Err(error) => {
+					log::error!("Failed waiting for lost surface to go idle: {error} - leaking it");
+					drop(lost_surface_deletions);
 				}
 			}
 
-			WindowEvent::RedrawRequested => {
-				self.time += std::f32::consts::PI / 60.0;
+			let result = gfx::PresentableSurface::new(&self.gfx_core, &window, &self.present_mode_preferences(), FRAMES_IN_FLIGHT, &self.color_space_preferences(), &self.composite_alpha_preferences());
 
-				let presentable_surface = self.presentable_surface.as_mut().unwrap();
-				let window = self.window.as_ref().unwrap();
+			let presentable_surface = match result {
+				Ok(presentable_surface) => presentable_surface,
+				Err(error) => {
+					// Nothing left to present to, so there's no point keeping the window around.
+					log::error!("Failed to recreate presentable surface, closing window: {error}");
+					continue
+				}
+			};
 
-				self.deletion_queue.destroy_ready(&self.gfx_core);
+			window.request_redraw();
 
-				let frame = match presentable_surface.start_frame(&self.gfx_core) {
-					Ok(frame) => frame,
-					Err(err) => {
-						log::error!("Unable to start frame: {err}");
+			self.windows.insert(window_id, AppWindow {
+				window,
+				presentable_surface,
+				depth_attachment,
+				redraw_requested,
+			});
+		}
+	}
 
-						let extent = presentable_surface.swapchain_extent;
+	fn close_window(&mut self, window_id: WindowId) {
+		let Some(AppWindow{ window, presentable_surface, depth_attachment, .. }) = self.windows.remove(&window_id) else {
+			return
+		};
 
-						// TODO(pat.m): YUCK
-						let _ = presentable_surface.resize(&self.gfx_core, &mut self.deletion_queue, vk::Extent2D{width: 0, height: 0});
-						let result = presentable_surface.resize(&self.gfx_core, &mut self.deletion_queue, extent);
-						if let Err(error) = result {
-							log::error!("Failed to resize presentable surface: {error}");
-							return;
-						};
+		// Queues its own deletion, so must be dropped before the deletion queue is borrowed.
+		drop(depth_attachment);
 
-						window.request_redraw();
-						return;
-					}
-				};
+		let vk_surface = presentable_surface.vk_surface();
+		presentable_surface.queue_deletion(&mut self.deletion_queue.borrow_mut());
 
-				let vk_cmd_buffer = frame.cmd_buffer();
-				let vk_swapchain_image = frame.swapchain_image_view();
+		window.set_visible(false);
+		self.closed_windows.push((vk_surface, window));
+	}
 
-				let render_area = vk::Rect2D {
-					offset: vk::Offset2D { x: 0, y: 0 },
-					extent: frame.extent,
-				};
 
-				#[derive(Copy, Clone, bytemuck::NoUninit)]
-				#[repr(C)]
-				struct GlobalBuffer {
-					projection_view: [[f32; 4]; 4],
-					time: f32,
+	/// Draw every window that requested a redraw, with a single submit and present.
+	fn draw_frame(&mut self) {
+		self.time += std::f32::consts::PI / 60.0;
+
+		{
+			let mut deletion_queue = self.deletion_queue.borrow_mut();
+			deletion_queue.destroy_ready(&self.gfx_core, &self.allocator);
+			self.closed_windows.retain(|(vk_surface, _)| deletion_queue.is_pending(vk_surface));
+		}
+
+		self.allocator.update_budgets(&self.gfx_core);
+
+		if let Err(error) = self.staging_buffer.release_idle_chunks(&self.gfx_core, &mut self.deletion_queue.borrow_mut()) {
+			log::error!("Failed to release idle staging chunks: {error}");
+		}
+
+		// Don't start on CPU work for this frame until the display has caught up.
+		for app_window in self.windows.values_mut() {
+			if !app_window.wants_redraw() {
+				continue
+			}
+
+			if let Err(error) = app_window.presentable_surface.wait_for_present(&self.gfx_core, MAX_PENDING_PRESENTS) {
+				log::error!("Failed to wait for present: {error}");
+			}
+		}
+
+		let frame_context = self.frame_context.as_mut().unwrap();
+
+		let mut frame = match frame_context.start_frame(&self.gfx_core) {
+			Ok(frame) => frame,
+			Err(err) => {
+				log::error!("Unable to start frame: {err}");
+				return;
+			}
+		};
+
+		if let Err(error) = self.uploader.flush(&self.gfx_core, &mut frame) {
+			log::error!("Failed to flush uploads: {error}");
+		}
+
+		let pipeline = self.pipeline.as_ref().unwrap();
+		let offsets_buffer = self.offsets_buffer.as_ref().unwrap();
+
+		frame.mark_used(pipeline);
+		frame.mark_used(offsets_buffer);
+
+		let vk_cmd_buffer = frame.cmd_buffer();
+		let mut used_depth_memory = Vec::new();
+
+		for app_window in self.windows.values_mut() {
+			if !app_window.wants_redraw() {
+				continue
+			}
+
+			app_window.redraw_requested = false;
+
+			// Keep redrawing continuously.
+			app_window.window.request_redraw();
+
+			let surface_image = match app_window.presentable_surface.acquire_image(&self.gfx_core, &mut self.deletion_queue.borrow_mut(), &mut frame) {
+				Ok(surface_image) => surface_image,
+				Err(err) => {
+					log::error!("Unable to acquire swapchain image: {err}");
+					continue
+				}
+			};
+
+			// The swapchain can be recreated at a different size or orientation by acquire_image, not just on resize.
+			if app_window.depth_attachment.as_ref().map(|depth_attachment| depth_attachment.extent) != Some(surface_image.extent) {
+				app_window.depth_attachment = None;
+
+				match DepthAttachment::new(&self.gfx_core, &self.allocator, &self.deletion_queue, surface_image.extent) {
+					Ok(depth_attachment) => app_window.depth_attachment = Some(depth_attachment),
+					Err(error) => log::error!("Failed to create depth attachment: {error}"),
 				}
+			}
+
+			// The image still gets presented, but there's nothing to render it with.
+			let Some(depth_attachment) = app_window.depth_attachment.as_ref() else {
+				continue
+			};
+
+			frame.mark_used(&depth_attachment.view);
+			frame.mark_used(&depth_attachment.image);
+			frame.mark_used(&depth_attachment.memory);
+			used_depth_memory.push(depth_attachment.memory.handle());
+
+			let render_area = vk::Rect2D {
+				offset: vk::Offset2D { x: 0, y: 0 },
+				extent: surface_image.extent,
+			};
+
+			#[derive(Copy, Clone, bytemuck::NoUninit)]
+			#[repr(C)]
+			struct GlobalBuffer {
+				projection_view: [[f32; 4]; 4],
+				time: f32,
+				output_encoding: u32,
+			}
 
-				let global_buffer_addr = self.staging_buffer.write(&GlobalBuffer {
-					projection_view: {
-						let aspect = frame.extent.width as f32 / frame.extent.height as f32;
-						let xsc = 1.0 / aspect;
-						let ysc = 1.0;
-						let zsc = -1.0 / 10.0;
-						let ztr = 1.0;
-
-						[
-							[xsc, 0.0, 0.0, 0.0],
-							[0.0, ysc, 0.0, 0.0],
-							[0.0, 0.0, zsc, 1.0],
-							[0.0, 0.0, ztr, 1.0],
-						]
-					},
-
-					time: self.time,
-				});
-
-				// Note: no barriers needed for host writes since vkQueueSubmit acts as an implicit memory barrier.
-
-				unsafe {
-					// Set dynamic state
-					self.gfx_core.vk_device.cmd_set_scissor(vk_cmd_buffer, 0, &[render_area]);
-					self.gfx_core.vk_device.cmd_set_viewport(vk_cmd_buffer, 0, &[vk::Viewport {
-						x: render_area.offset.x as f32,
-						y: render_area.offset.y as f32,
-						width: render_area.extent.width as f32,
-						height: render_area.extent.height as f32,
-						min_depth: 0.0,
-						max_depth: 1.0,
-					}]);
-
-					let color_attachments = [
-						vk::RenderingAttachmentInfo::default()
-							.image_view(vk_swapchain_image)
-							.image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-							.load_op(vk::AttachmentLoadOp::CLEAR)
-							.store_op(vk::AttachmentStoreOp::STORE)
-							.clear_value(vk::ClearValue {
-								color: vk::ClearColorValue {
-									float32: [1.0, 0.5, 1.0, 1.0],
-								},
-							})
-					];
-
-					let depth_attachment = vk::RenderingAttachmentInfo::default()
-						.image_view(self.vk_depth_view)
-						.image_layout(vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL)
+			let write_result = self.staging_buffer.write(&self.gfx_core, &self.allocator, &GlobalBuffer {
+				projection_view: {
+					let display_extent = surface_image.display_extent();
+					let aspect = display_extent.width as f32 / display_extent.height as f32;
+					let xsc = 1.0 / aspect;
+					let ysc = 1.0;
+					let zsc = -1.0 / 10.0;
+					let ztr = 1.0;
+
+					// Rotate clip space to match the surface transform, so the presentation engine doesn't have to.
+					let (cos, sin) = clip_space_rotation(surface_image.transform);
+
+					[
+						[xsc*cos, xsc*sin, 0.0, 0.0],
+						[-ysc*sin, ysc*cos, 0.0, 0.0],
+						[0.0, 0.0, zsc, 1.0],
+						[0.0, 0.0, ztr, 1.0],
+					]
+				},
+
+				time: self.time,
+				output_encoding: output_encoding(surface_image.color_space),
+			});
+
+			// As above, the image still gets presented, just without anything drawn to it.
+			let global_buffer_ptr = match write_result {
+				Ok(global_buffer_ptr) => global_buffer_ptr,
+				Err(error) => {
+					log::error!("Failed to write global buffer, skipping draw: {error}");
+					continue
+				}
+			};
+
+			// Note: no barriers needed for host writes since vkQueueSubmit acts as an implicit memory barrier.
+
+			unsafe {
+				// Set dynamic state
+				self.gfx_core.vk_device.cmd_set_scissor(vk_cmd_buffer, 0, &[render_area]);
+				self.gfx_core.vk_device.cmd_set_viewport(vk_cmd_buffer, 0, &[vk::Viewport {
+					x: render_area.offset.x as f32,
+					y: render_area.offset.y as f32,
+					width: render_area.extent.width as f32,
+					height: render_area.extent.height as f32,
+					min_depth: 0.0,
+					max_depth: 1.0,
+				}]);
+
+				let color_attachments = [
+					vk::RenderingAttachmentInfo::default()
+						.image_view(surface_image.vk_image_view)
+						.image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
 						.load_op(vk::AttachmentLoadOp::CLEAR)
-						.store_op(vk::AttachmentStoreOp::DONT_CARE)
+						.store_op(vk::AttachmentStoreOp::STORE)
 						.clear_value(vk::ClearValue {
-							depth_stencil: vk::ClearDepthStencilValue {
-								depth: 0.0,
-								stencil: 0,
+							color: vk::ClearColorValue {
+								float32: clear_color(&surface_image),
 							},
-						});
+						})
+				];
+
+				let depth_attachment = vk::RenderingAttachmentInfo::default()
+					.image_view(depth_attachment.view.handle())
+					.image_layout(vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL)
+					.load_op(vk::AttachmentLoadOp::CLEAR)
+					.store_op(vk::AttachmentStoreOp::DONT_CARE)
+					.clear_value(vk::ClearValue {
+						depth_stencil: vk::ClearDepthStencilValue {
+							depth: 0.0,
+							stencil: 0,
+						},
+					});
+
+				let render_info = vk::RenderingInfo::default()
+					.layer_count(1)
+					.render_area(render_area)
+					.color_attachments(&color_attachments)
+					.depth_attachment(&depth_attachment);
+
+				self.gfx_core.vk_device.cmd_begin_rendering(vk_cmd_buffer, &render_info);
+
+				// Draw
+				self.gfx_core.vk_device.cmd_bind_pipeline(vk_cmd_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.handle());
+				self.gfx_core.vk_device.cmd_push_constants(vk_cmd_buffer, self.vk_pipeline_layout, vk::ShaderStageFlags::ALL_GRAPHICS, 0, bytemuck::bytes_of(&global_buffer_ptr));
+
+				for index in 0..offsets_buffer.len() {
+					let per_draw_ptr = offsets_buffer.element_ptr(index);
+					self.gfx_core.vk_device.cmd_push_constants(vk_cmd_buffer, self.vk_pipeline_layout, vk::ShaderStageFlags::ALL_GRAPHICS, 8, bytemuck::bytes_of(&per_draw_ptr));
+					self.gfx_core.vk_device.cmd_draw(vk_cmd_buffer, 3, 1, 0, 0);
+				}
 
-					let render_info = vk::RenderingInfo::default()
-						.layer_count(1)
-						.render_area(render_area)
-						.color_attachments(&color_attachments)
-						.depth_attachment(&depth_attachment);
-
-					self.gfx_core.vk_device.cmd_begin_rendering(vk_cmd_buffer, &render_info);
-
-					// Draw
-					self.gfx_core.vk_device.cmd_bind_pipeline(vk_cmd_buffer, vk::PipelineBindPoint::GRAPHICS, self.vk_pipeline);
-					self.gfx_core.vk_device.cmd_push_constants(vk_cmd_buffer, self.vk_pipeline_layout, vk::ShaderStageFlags::ALL_GRAPHICS, 0, bytemuck::bytes_of(&global_buffer_addr));
-
-					let offsets = [
-						[0.0f32, 0.0, 0.0, 0.0],
-						[1.0, 0.0, 1.0, 3.0],
-						[-1.0, 0.0, 3.0, 6.0],
-						[-0.5, 1.0, 2.0, 9.0],
-					];
-
-					for offset in offsets {
-						let per_draw_addr = self.staging_buffer.write(&offset);
-						self.gfx_core.vk_device.cmd_push_constants(vk_cmd_buffer, self.vk_pipeline_layout, vk::ShaderStageFlags::ALL_GRAPHICS, 8, bytemuck::bytes_of(&per_draw_addr));
-						self.gfx_core.vk_device.cmd_draw(vk_cmd_buffer, 3, 1, 0, 0);
+				self.gfx_core.vk_device.cmd_end_rendering(vk_cmd_buffer);
+			}
+
+			app_window.window.pre_present_notify();
+		}
+
+		match frame_context.submit_frame(&self.gfx_core, frame) {
+			Ok(timeline_value) => {
+				self.staging_buffer.mark_submitted(timeline_value);
+
+				for vk_memory in used_depth_memory {
+					self.allocator.mark_used(vk_memory, timeline_value);
+				}
+			}
+
+			// Anything written for this frame stays pending, and goes out with the next submit.
+			Err(error) => log::error!("Failed to submit frame: {error}"),
+		}
+
+		if self.last_present_interval_report.elapsed() >= Duration::from_secs(1) {
+			self.last_present_interval_report = Instant::now();
+
+			for (window_id, app_window) in self.windows.iter() {
+				if let Some(present_interval) = app_window.presentable_surface.present_interval() {
+					log::info!("{window_id:?} present interval: {:.2}ms", present_interval.as_secs_f64() * 1000.0);
+				}
+			}
+		}
+	}
+}
+
+impl ApplicationHandler for App {
+	fn resumed(&mut self, event_loop: &ActiveEventLoop) {
+		if self.windows.is_empty() {
+			self.open_window(event_loop).unwrap();
+		}
+	}
+
+	fn window_event(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
+		match event {
+			WindowEvent::CloseRequested => {
+				self.close_window(window_id);
+
+				if self.windows.is_empty() {
+					event_loop.exit();
+				}
+			},
+
+			WindowEvent::KeyboardInput{ event, .. }
+				if event.state == ElementState::Pressed && !event.repeat && event.physical_key == PhysicalKey::Code(KeyCode::KeyV) =>
+			{
+				self.vsync = !self.vsync;
+
+				let present_mode_preferences = self.present_mode_preferences();
+
+				for app_window in self.windows.values_mut() {
+					match app_window.presentable_surface.set_present_mode(&self.gfx_core, &mut self.deletion_queue.borrow_mut(), &present_mode_preferences) {
+						Ok(present_mode) => log::info!("Vsync {} - using {present_mode:?}", if self.vsync { "on" } else { "off" }),
+						Err(error) => log::error!("Failed to change present mode: {error}"),
 					}
+				}
+			}
 
-					self.gfx_core.vk_device.cmd_end_rendering(vk_cmd_buffer);
+			WindowEvent::KeyboardInput{ event, .. }
+				if event.state == ElementState::Pressed && !event.repeat && event.physical_key == PhysicalKey::Code(KeyCode::KeyN) =>
+			{
+				if let Err(error) = self.open_window(event_loop) {
+					log::error!("Failed to open window: {error}");
 				}
+			}
 
-				window.pre_present_notify();
+			WindowEvent::Resized(PhysicalSize{ width, height }) => {
+				let Some(app_window) = self.windows.get_mut(&window_id) else {
+					return
+				};
 
-				if let Err(error) = presentable_surface.submit_frame(&self.gfx_core, frame) {
-					log::error!("Present failed: {error}");
+				let result = app_window.presentable_surface.resize(&self.gfx_core, &mut self.deletion_queue.borrow_mut(), vk::Extent2D{width, height});
+				if let Err(error) = result {
+					log::error!("Failed to resize presentable surface: {error}");
+				};
+
+				// Restart the redraw loop if it was paused for being zero-sized.
+				if app_window.presentable_surface.state() == gfx::SurfaceState::Active {
+					app_window.window.request_redraw();
 				}
+			}
+
+			WindowEvent::Occluded(occluded) => {
+				let Some(app_window) = self.windows.get_mut(&window_id) else {
+					return
+				};
+
+				log::info!("{window_id:?} occluded: {occluded}");
+
+				app_window.presentable_surface.set_occluded(occluded);
+
+				if !occluded {
+					app_window.window.request_redraw();
+				}
+			}
 
-				window.request_redraw();
+			WindowEvent::RedrawRequested => {
+				if let Some(app_window) = self.windows.get_mut(&window_id) {
+					app_window.redraw_requested = true;
+				}
 			}
+
 			_ => (),
 		}
 	}
 
+	fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+		self.recreate_lost_surfaces();
+
+		if !self.windows.values().any(AppWindow::wants_redraw) {
+			// Nothing to draw, so sleep until the next event rather than spinning.
+			event_loop.set_control_flow(ControlFlow::Wait);
+			return
+		}
+
+		event_loop.set_control_flow(ControlFlow::Poll);
+
+		if let Some(min_frame_interval) = self.min_frame_interval {
+			let now = Instant::now();
+			if now < self.next_frame_time {
+				event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_frame_time));
+				return
+			}
+
+			// Don't try to catch up on frames that were missed.
+			self.next_frame_time = (self.next_frame_time + min_frame_interval).max(now);
+			event_loop.set_control_flow(ControlFlow::WaitUntil(self.next_frame_time));
+		}
+
+		self.draw_frame();
+	}
+
 	fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
-		self.destroy_depth_attachment();
+		log::debug!("Allocator statistics: {}", self.allocator.build_stats_json(true));
 
-		self.deletion_queue.queue_deletion(self.vk_pipeline, &self.gfx_core);
+		let window_ids: Vec<_> = self.windows.keys().copied().collect();
+		for window_id in window_ids {
+			self.close_window(window_id);
+		}
+
+		self.pipeline = None;
 
-		if let Some(presentable_surface) = self.presentable_surface.take() {
-			presentable_surface.queue_deletion(&mut self.deletion_queue);
+		let mut deletion_queue = self.deletion_queue.borrow_mut();
+
+		if let Some(frame_context) = self.frame_context.take() {
+			frame_context.queue_deletion(&self.gfx_core, &mut deletion_queue);
 		}
 
-		self.staging_buffer.queue_deletion(&mut self.deletion_queue);
+		if let Some(offsets_buffer) = self.offsets_buffer.take() {
+			offsets_buffer.queue_deletion(&mut deletion_queue);
+		}
+
+		self.uploader.queue_deletion(&self.gfx_core, &mut deletion_queue);
+		self.staging_buffer.queue_deletion(&mut deletion_queue);
+
+		deletion_queue.queue_deletion(self.vk_pipeline_layout, &self.gfx_core);
 
 		self.gfx_core.wait_idle();
 
 		unsafe {
-			self.deletion_queue.destroy_all_immediate(&self.gfx_core);
+			deletion_queue.destroy_all_immediate(&self.gfx_core, &self.allocator);
+		}
+	}
+}
+
 
-			// TODO(pat.m): deletion queue! although these can probably be destroyed as soon as we're done with them
-			self.gfx_core.vk_device.destroy_pipeline_layout(self.vk_pipeline_layout, None);
+
+/// Background colour, half transparent if the surface composites with alpha.
+fn clear_color(surface_image: &gfx::SurfaceImage) -> [f32; 4] {
+	let [r, g, b] = [1.0, 0.5, 1.0];
+	let alpha = 0.5;
+
+	match surface_image.composite_alpha {
+		// The compositor sees encoded values, so with an sRGB view the colour has to be premultiplied after encoding.
+		vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED if surface_image.has_srgb_view() => {
+			let [r, g, b] = [r, g, b].map(|c| srgb_eotf(srgb_oetf(c) * alpha));
+			[r, g, b, alpha]
 		}
+
+		vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED => [r * alpha, g * alpha, b * alpha, alpha],
+		vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED => [r, g, b, alpha],
+		_ => [r, g, b, 1.0],
 	}
 }
 
+fn srgb_oetf(linear: f32) -> f32 {
+	match linear <= 0.0031308 {
+		true => linear * 12.92,
+		false => 1.055 * linear.powf(1.0 / 2.4) - 0.055,
+	}
+}
+
+fn srgb_eotf(encoded: f32) -> f32 {
+	match encoded <= 0.04045 {
+		true => encoded / 12.92,
+		false => ((encoded + 0.055) / 1.055).powf(2.4),
+	}
+}
+
+/// Cosine and sine of the rotation to apply to clip space for `transform`.
+// TODO(pat.m): mirrored transforms are treated as unmirrored.
+fn clip_space_rotation(transform: vk::SurfaceTransformFlagsKHR) -> (f32, f32) {
+	use vk::SurfaceTransformFlagsKHR as T;
 
+	match transform {
+		T::ROTATE_90 | T::HORIZONTAL_MIRROR_ROTATE_90 => (0.0, 1.0),
+		T::ROTATE_180 | T::HORIZONTAL_MIRROR_ROTATE_180 => (-1.0, 0.0),
+		T::ROTATE_270 | T::HORIZONTAL_MIRROR_ROTATE_270 => (0.0, -1.0),
+		_ => (1.0, 0.0),
+	}
+}
+
+/// How the fragment shader should encode its output - must match the OUTPUT_ENCODING_* constants in main.fs.glsl.
+fn output_encoding(color_space: vk::ColorSpaceKHR) -> u32 {
+	match color_space {
+		vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT => 1,
+		vk::ColorSpaceKHR::HDR10_ST2084_EXT => 2,
+		vk::ColorSpaceKHR::DISPLAY_P3_NONLINEAR_EXT => 3,
+
+		// sRGB encoding is handled by the swapchain image view.
+		_ => 0,
+	}
+}
 
-fn create_shader_module(device: &ash::Device, path: impl AsRef<std::path::Path>) -> anyhow::Result<vk::ShaderModule> {
+fn create_shader_module(core: &gfx::Core, path: impl AsRef<std::path::Path>) -> anyhow::Result<vk::ShaderModule> {
 	let contents = std::fs::read(path)?;
 	anyhow::ensure!(contents.len() % 4 == 0);
 
@@ -382,7 +810,7 @@ fn create_shader_module(device: &ash::Device, path: impl AsRef<std::path::Path>)
 		let create_info = vk::ShaderModuleCreateInfo::default()
 			.code(contents);
 
-		Ok(device.create_shader_module(&create_info, None)?)
+		Ok(core.vk_device.create_shader_module(&create_info, core.allocation_callbacks())?)
 	}
 }
 
@@ -445,7 +873,7 @@ fn create_graphics_pipeline(core: &gfx::Core, vert_sh: vk::ShaderModule, frag_sh
 		.depth_attachment_format(vk::Format::D32_SFLOAT);
 
 	unsafe {
-		let vk_pipeline_layout = core.vk_device.create_pipeline_layout(&pipeline_layout_info, None)?;
+		let vk_pipeline_layout = core.vk_device.create_pipeline_layout(&pipeline_layout_info, core.allocation_callbacks())?;
 
 		let graphic_pipeline_create_infos = [
 			vk::GraphicsPipelineCreateInfo::default()
@@ -461,7 +889,7 @@ fn create_graphics_pipeline(core: &gfx::Core, vert_sh: vk::ShaderModule, frag_sh
 				.push_next(&mut rendering_create_info)
 		];
 
-		let pipelines = core.vk_device.create_graphics_pipelines(vk::PipelineCache::null(), &graphic_pipeline_create_infos, None)
+		let pipelines = core.vk_device.create_graphics_pipelines(vk::PipelineCache::null(), &graphic_pipeline_create_infos, core.allocation_callbacks())
 			.map_err(|(_, err)| err)?;
 
 		Ok((pipelines[0], vk_pipeline_layout))